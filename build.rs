@@ -0,0 +1,136 @@
+//! Generates the C header from the C API in `src/ffi.rs`, into `OUT_DIR`.
+//! The copy checked in as `include/clacjit.h` is compared with it by
+//! `tests/ffi.rs`, so that it can not go stale.
+//!
+//! Only the forms used there are translated: `c_int` constants, opaque
+//! structs, and `extern "C"` functions taking and returning plain values
+//! and pointers. Doc comments are copied, up to their `# Safety` section.
+
+use std::path::PathBuf;
+
+const FFI: &str = "src/ffi.rs";
+
+fn c_type(ty: &str) -> String {
+    let ty = ty.trim();
+    if let Some(pointee) = ty.strip_prefix("*const ") {
+        return format!("const {} *", c_type(pointee));
+    }
+    if let Some(pointee) = ty.strip_prefix("*mut ") {
+        return format!("{} *", c_type(pointee));
+    }
+    match ty {
+        "c_int" => "int",
+        "c_char" => "char",
+        "i32" => "int32_t",
+        "usize" => "size_t",
+        "()" => "void",
+        // Opaque structs keep their name
+        _ => ty,
+    }
+    .to_string()
+}
+
+/// `T *` and `T` both read well with the name right after
+fn declare(ty: &str, name: &str) -> String {
+    let ty = c_type(ty);
+    match ty.ends_with('*') {
+        true => format!("{}{}", ty, name),
+        false => format!("{} {}", ty, name),
+    }
+}
+
+/// Translate `pub extern "C" fn name(a: A, b: B) -> R`, without the body
+fn function(signature: &str) -> String {
+    let (_, rest) = signature.split_once("fn ").unwrap();
+    let (name, rest) = rest.split_once('(').unwrap();
+    let (params, ret) = rest.rsplit_once(')').unwrap();
+    let ret = ret.trim().strip_prefix("->").unwrap_or("()");
+    let params: Vec<String> = params
+        .split(',')
+        .filter(|param| !param.trim().is_empty())
+        .map(|param| {
+            let (name, ty) = param.split_once(':').unwrap();
+            declare(ty, name.trim())
+        })
+        .collect();
+    let params = match params.is_empty() {
+        true => "void".to_string(),
+        false => params.join(", "),
+    };
+    format!("{}({});", declare(ret, name.trim()), params)
+}
+
+fn header(source: &str) -> String {
+    let mut out = String::new();
+    out.push_str("/* Generated by build.rs from src/ffi.rs, do not edit */\n\n");
+    out.push_str("#ifndef CLACJIT_H\n#define CLACJIT_H\n\n");
+    out.push_str("#include <stddef.h>\n#include <stdint.h>\n\n");
+    out.push_str("#ifdef __cplusplus\nextern \"C\" {\n#endif\n");
+
+    let mut docs: Vec<String> = vec![];
+    let mut safety = false;
+    let mut lines = source.lines();
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if let Some(doc) = line.strip_prefix("///") {
+            safety |= doc.trim() == "# Safety";
+            if !safety {
+                docs.push(doc.trim().to_string());
+            }
+            continue;
+        }
+        if line.starts_with("#[") {
+            continue;
+        }
+
+        let item = if let Some(rest) = line.strip_prefix("pub const ") {
+            let (name, rest) = rest.split_once(':').unwrap();
+            let (_, value) = rest.split_once('=').unwrap();
+            let value = value.trim_end_matches(';').trim();
+            match value.starts_with('-') {
+                true => Some(format!("#define {} ({})", name, value)),
+                false => Some(format!("#define {} {}", name, value)),
+            }
+        } else if let Some(rest) = line.strip_prefix("pub struct ") {
+            let name = rest.trim_end_matches('{').trim();
+            Some(format!("typedef struct {} {};", name, name))
+        } else if line.contains("extern \"C\" fn ") {
+            let mut signature = line.to_string();
+            while !signature.ends_with('{') {
+                signature.push_str(lines.next().unwrap().trim());
+            }
+            Some(function(signature.trim_end_matches('{')))
+        } else {
+            None
+        };
+
+        if let Some(item) = item {
+            while docs.last().is_some_and(|doc| doc.is_empty()) {
+                docs.pop();
+            }
+            if !docs.is_empty() {
+                out.push('\n');
+            }
+            for doc in &docs {
+                out.push_str(format!("// {}", doc).trim_end());
+                out.push('\n');
+            }
+            out.push_str(&item);
+            out.push('\n');
+        }
+        docs.clear();
+        safety = false;
+    }
+
+    out.push_str("\n#ifdef __cplusplus\n}\n#endif\n\n#endif\n");
+    out
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", FFI);
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let source = std::fs::read_to_string(FFI).unwrap();
+    let out = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    std::fs::write(out.join("clacjit.h"), header(&source)).unwrap();
+}
@@ -0,0 +1,263 @@
+//! `clac!`, for clac programs written right in Rust source.
+//!
+//! The program is split into tokens when the Rust code is compiled, with
+//! the same `parse` as `State::parse`, and checked, so that broken programs
+//! are compile errors:
+//!
+//! - definitions must be closed, named by a word, and not nested
+//! - builtins and words defined in the program must find the values they
+//!   pop, counting from an empty stack. The count stops at the first `if`,
+//!   `skip` or `quit`, and at words the program does not define.
+
+use std::collections::HashMap;
+
+use clacjit::Token;
+use proc_macro::{Span, TokenStream, TokenTree};
+use quote::{format_ident, quote, quote_spanned};
+
+/// A token, and the Rust tokens it was written as
+struct Spanned {
+    token: Token,
+    text: String,
+    span: Span,
+}
+
+struct Error {
+    span: Span,
+    message: String,
+}
+
+impl Error {
+    fn new(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            span,
+            message: message.into(),
+        }
+    }
+}
+
+fn touching(end: Span, start: Span) -> bool {
+    end.line() == start.line() && end.column() == start.column()
+}
+
+/// Words are what clac splits on whitespace: Rust tokens written without
+/// space between them, like `-` and `5`, or `*` and `*`, are one word
+fn words(input: TokenStream) -> Result<Vec<(String, Span)>, Error> {
+    let mut words: Vec<(String, Span)> = vec![];
+    let mut last_end = None;
+    for tree in input {
+        if let TokenTree::Group(group) = &tree {
+            return Err(Error::new(group.span(), "clac has no brackets"));
+        }
+        let span = tree.span();
+        match (words.last_mut(), last_end) {
+            (Some((word, _)), Some(end)) if touching(end, span.start()) => {
+                word.push_str(&tree.to_string())
+            }
+            _ => words.push((tree.to_string(), span)),
+        }
+        last_end = Some(span.end());
+    }
+    Ok(words)
+}
+
+/// Values a token pops, at most, before it pushes anything, and how many
+/// it leaves in their place, unless that depends on what runs
+#[derive(Clone, Copy)]
+struct Effect {
+    needs: usize,
+    leaves: Option<usize>,
+}
+
+impl Effect {
+    fn new(needs: usize, leaves: usize) -> Self {
+        Self {
+            needs,
+            leaves: Some(leaves),
+        }
+    }
+
+    fn unknown(needs: usize) -> Self {
+        Self {
+            needs,
+            leaves: None,
+        }
+    }
+}
+
+fn effect(token: &Token, before: Option<&Token>, defs: &HashMap<String, Effect>) -> Effect {
+    use Token::*;
+    match token {
+        Num(_) => Effect::new(0, 1),
+        Add | Sub | Mul | Div | Mod | Pow | Less => Effect::new(2, 1),
+        Swap => Effect::new(2, 2),
+        Rot => Effect::new(3, 3),
+        Drop | Print => Effect::new(1, 0),
+        // The index, and as many values under it
+        Pick => match before {
+            Some(Num(n)) if *n > 0 => Effect::new(*n as usize + 1, *n as usize + 1),
+            _ => Effect::unknown(1),
+        },
+        If | Skip => Effect::unknown(1),
+        Custom(name) => defs.get(name).copied().unwrap_or(Effect::unknown(0)),
+        _ => Effect::unknown(0),
+    }
+}
+
+/// Effect of a definition, from its body
+fn body_effect(body: &[Spanned], defs: &HashMap<String, Effect>) -> Effect {
+    let mut needs = 0;
+    let mut depth = 0;
+    for (i, spanned) in body.iter().enumerate() {
+        let before = i.checked_sub(1).map(|i| &body[i].token);
+        let effect = effect(&spanned.token, before, defs);
+        if depth < effect.needs {
+            // Values the caller has to give
+            needs += effect.needs - depth;
+            depth = effect.needs;
+        }
+        match effect.leaves {
+            Some(leaves) => depth = depth - effect.needs + leaves,
+            None => return Effect::unknown(needs),
+        }
+    }
+    Effect::new(needs, depth)
+}
+
+fn values(n: usize) -> String {
+    match n {
+        1 => "1 value".to_string(),
+        n => format!("{} values", n),
+    }
+}
+
+fn check(tokens: &[Spanned]) -> Result<(), Error> {
+    let mut defs = HashMap::new();
+    // Values on the stack, while that is known
+    let mut depth = Some(0);
+    let mut i = 0;
+    while i < tokens.len() {
+        let spanned = &tokens[i];
+        match &spanned.token {
+            Token::DefBegin => {
+                let Some(len) = tokens[i + 1..]
+                    .iter()
+                    .position(|t| t.token == Token::DefEnd)
+                else {
+                    return Err(Error::new(spanned.span, "`:` is not closed by `;`"));
+                };
+                let def = &tokens[i + 1..i + 1 + len];
+                let Some((name, body)) = def.split_first() else {
+                    return Err(Error::new(spanned.span, "Empty definition"));
+                };
+                let Token::Custom(word) = &name.token else {
+                    return Err(Error::new(
+                        name.span,
+                        format!("`{}` can not be defined", name.text),
+                    ));
+                };
+                if let Some(nested) = body.iter().find(|t| t.token == Token::DefBegin) {
+                    return Err(Error::new(nested.span, "Definitions can not be nested"));
+                }
+                if word != "comment" {
+                    // Calls to itself are not known yet
+                    defs.remove(word);
+                    let effect = body_effect(body, &defs);
+                    defs.insert(word.clone(), effect);
+                }
+                i += len + 2;
+                continue;
+            }
+            Token::DefEnd => return Err(Error::new(spanned.span, "`;` without `:`")),
+            token => {
+                if let Some(d) = depth {
+                    let before = i.checked_sub(1).map(|i| &tokens[i].token);
+                    let effect = effect(token, before, &defs);
+                    if d < effect.needs {
+                        return Err(Error::new(
+                            spanned.span,
+                            format!(
+                                "`{}` needs {}, but the stack would have {}",
+                                spanned.text,
+                                values(effect.needs),
+                                d
+                            ),
+                        ));
+                    }
+                    depth = effect.leaves.map(|leaves| d - effect.needs + leaves);
+                }
+            }
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+fn token_expr(token: &Token) -> proc_macro2::TokenStream {
+    match token {
+        Token::Num(n) => quote!(::clacjit::Token::Num(#n)),
+        Token::Custom(name) => {
+            quote!(::clacjit::Token::Custom(::std::string::String::from(#name)))
+        }
+        token => {
+            let variant = format_ident!("{}", format!("{:?}", token));
+            quote!(::clacjit::Token::#variant)
+        }
+    }
+}
+
+/// A clac program, checked and parsed at compile time. Expands to a
+/// function that queues it on a `State` and evaluates it:
+///
+/// ```
+/// # use clacjit_macros::clac;
+/// # fn main() -> Result<(), clacjit::ClacError> {
+/// let program = clac! { : sq 1 pick * ; 7 sq };
+/// let mut state = clacjit::State::new();
+/// program(&mut state)?;
+/// assert_eq!(state.stack(), &[49]);
+/// # Ok(())
+/// # }
+/// ```
+#[proc_macro]
+pub fn clac(input: TokenStream) -> TokenStream {
+    let tokens = words(input).and_then(|words| {
+        let mut tokens = vec![];
+        for (text, span) in words {
+            let mut parsed = clacjit::parse(&text);
+            while let Some(token) = parsed.pop() {
+                tokens.push(Spanned {
+                    token,
+                    text: text.clone(),
+                    span,
+                });
+            }
+        }
+        check(&tokens)?;
+        Ok(tokens)
+    });
+
+    let tokens = match tokens {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            let message = e.message;
+            return quote_spanned!(e.span.into()=> compile_error!(#message)).into();
+        }
+    };
+
+    let len = tokens.len();
+    let tokens = tokens.iter().map(|spanned| token_expr(&spanned.token));
+    quote! {{
+        fn program(
+            state: &mut ::clacjit::State,
+        ) -> ::std::result::Result<::clacjit::Outcome, ::clacjit::ClacError> {
+            let tokens: [::clacjit::Token; #len] = [#(#tokens),*];
+            for token in tokens {
+                state.queue.push(token);
+            }
+            ::clacjit::eval(state)
+        }
+        program
+    }}
+    .into()
+}
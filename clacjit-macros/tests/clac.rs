@@ -0,0 +1,28 @@
+use clacjit::{ClacError, Outcome, State};
+use clacjit_macros::clac;
+
+#[test]
+fn runs_the_program() {
+    let program = clac! { : sq 1 pick * ; 7 sq };
+    let mut state = State::new();
+    assert_eq!(program(&mut state), Ok(Outcome::Done));
+    assert_eq!(state.stack(), &[49]);
+
+    // Definitions stay for code run later
+    assert_eq!(state.run_str("3 sq").unwrap(), &[49, 9]);
+}
+
+#[test]
+fn words_are_split_like_source() {
+    let program = clac! { -2 3 ** 5 - 1 -1 < };
+    let mut state = State::new();
+    program(&mut state).unwrap();
+    assert_eq!(state.stack(), &[-13, 0]);
+}
+
+#[test]
+fn runtime_errors_are_returned() {
+    let program = clac! { 1 0 / };
+    let mut state = State::new();
+    assert_eq!(program(&mut state), Err(ClacError::DivisionByZero));
+}
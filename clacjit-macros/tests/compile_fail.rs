@@ -0,0 +1,7 @@
+//! Programs `clac!` rejects, with the errors it gives.
+
+#[test]
+fn broken_programs() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/fail/*.rs");
+}
@@ -0,0 +1,5 @@
+use clacjit_macros::clac;
+
+fn main() {
+    let _ = clac! { 1 : sq 1 pick * };
+}
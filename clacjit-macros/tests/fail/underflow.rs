@@ -0,0 +1,5 @@
+use clacjit_macros::clac;
+
+fn main() {
+    let _ = clac! { : sq 1 pick * ; 1 2 + sq + };
+}
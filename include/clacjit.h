@@ -0,0 +1,62 @@
+/* Generated by build.rs from src/ffi.rs, do not edit */
+
+#ifndef CLACJIT_H
+#define CLACJIT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+// Backends for `clac_state_new`
+#define CLAC_INTERPRETER 0
+#define CLAC_BYTECODE 1
+#define CLAC_JIT 2
+
+// Results of `clac_load` and `clac_run`
+#define CLAC_DONE 0
+#define CLAC_QUIT 1
+#define CLAC_ERROR (-1)
+
+// A state, with the error of the last call that failed
+typedef struct ClacState ClacState;
+
+// Create a state with one of the backends. Returns NULL for an unknown
+// backend, or for the JIT in builds without it. The JIT checks everything
+// the interpreter does, so that broken programs fail the same way.
+ClacState *clac_state_new(int backend);
+
+// Destroy a state. NULL is ignored.
+void clac_state_free(ClacState *state);
+
+// Queue source to be run by `clac_run`. Fails if it is not UTF-8.
+int clac_load(ClacState *state, const char *source);
+
+// Run everything queued. Returns `CLAC_QUIT` if the program ran `quit`,
+// and `CLAC_ERROR` if it failed, with the rest of the queue dropped.
+// After an internal error, the state can only be freed.
+int clac_run(ClacState *state);
+
+// Number of values on the stack
+size_t clac_stack_len(const ClacState *state);
+
+// Values on the stack, bottom first, valid until the state changes
+const int32_t *clac_stack(const ClacState *state);
+
+// Push a value, as input for the program
+void clac_push(ClacState *state, int32_t value);
+
+// Drop every value on the stack
+void clac_clear_stack(ClacState *state);
+
+// Message of the error from the last `clac_load` or `clac_run`, or NULL
+// if it succeeded. Valid until the next call on the state.
+const char *clac_last_error(const ClacState *state);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,555 @@
+//! Ahead-of-time compiler.
+//!
+//! Translates a whole clac program (definitions and top-level tokens) into
+//! x86-64 assembly, then assembles and links it together with a tiny runtime
+//! into a static Linux executable.
+//!
+//! Register usage in the generated code:
+//! - rbx: next free slot of the clac stack (grows upwards, 4 bytes per value)
+//! - r12: bottom of the clac stack, for underflow checks
+//! - r13: top of the clac stack, for overflow checks
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{ClacError, Queue, Token};
+
+/// Number of values the clac stack of a compiled program can hold.
+const STACK_SIZE: usize = 1 << 24;
+
+/// Runtime linked into every executable: entry point, print and errors.
+const RUNTIME: &str = r#"
+    .text
+    .globl _start
+_start:
+    lea r12, [rip + clac_stack]
+    mov rbx, r12
+    lea r13, [r12 + 4 * STACK_SIZE]
+    call clac_fn_0
+clac_quit:
+    mov eax, 60
+    xor edi, edi
+    syscall
+
+# edi: value to print
+clac_print:
+    sub rsp, 32
+    lea rsi, [rsp + 32]
+    dec rsi
+    mov byte ptr [rsi], 10
+    mov eax, edi
+    mov r8d, edi
+    test eax, eax
+    jns 1f
+    neg eax
+1:
+    mov ecx, 10
+2:
+    xor edx, edx
+    div ecx
+    add dl, 48
+    dec rsi
+    mov [rsi], dl
+    test eax, eax
+    jnz 2b
+    test r8d, r8d
+    jns 3f
+    dec rsi
+    mov byte ptr [rsi], 45
+3:
+    lea rdx, [rsp + 32]
+    sub rdx, rsi
+    mov eax, 1
+    mov edi, 1
+    syscall
+    add rsp, 32
+    ret
+
+# ecx: exponent, eax: base. Result in eax
+clac_pow:
+    mov edx, 1
+1:
+    test ecx, ecx
+    jz 3f
+    test ecx, 1
+    jz 2f
+    imul edx, eax
+2:
+    imul eax, eax
+    shr ecx, 1
+    jmp 1b
+3:
+    mov eax, edx
+    ret
+
+# rsi: message, rdx: length
+clac_error:
+    mov eax, 1
+    mov edi, 2
+    syscall
+    mov eax, 60
+    mov edi, 1
+    syscall
+
+# rsi: name, rdx: length
+clac_unknown:
+    push rsi
+    push rdx
+    lea rsi, [rip + clac_msg_unknown]
+    mov edx, offset clac_msg_unknown_len
+    mov eax, 1
+    mov edi, 2
+    syscall
+    pop rdx
+    pop rsi
+    mov eax, 1
+    mov edi, 2
+    syscall
+    lea rsi, [rip + clac_msg_newline]
+    mov edx, 1
+    jmp clac_error
+
+clac_underflow:
+    lea rsi, [rip + clac_msg_underflow]
+    mov edx, offset clac_msg_underflow_len
+    jmp clac_error
+
+clac_overflow:
+    lea rsi, [rip + clac_msg_overflow]
+    mov edx, offset clac_msg_overflow_len
+    jmp clac_error
+
+clac_bad_index:
+    lea rsi, [rip + clac_msg_bad_index]
+    mov edx, offset clac_msg_bad_index_len
+    jmp clac_error
+
+clac_bad_skip:
+    lea rsi, [rip + clac_msg_bad_skip]
+    mov edx, offset clac_msg_bad_skip_len
+    jmp clac_error
+
+clac_def_end:
+    lea rsi, [rip + clac_msg_def_end]
+    mov edx, offset clac_msg_def_end_len
+    jmp clac_error
+
+clac_div_zero:
+    lea rsi, [rip + clac_msg_div_zero]
+    mov edx, offset clac_msg_div_zero_len
+    jmp clac_error
+
+clac_int_overflow:
+    lea rsi, [rip + clac_msg_int_overflow]
+    mov edx, offset clac_msg_int_overflow_len
+    jmp clac_error
+
+clac_neg_exponent:
+    lea rsi, [rip + clac_msg_neg_exponent]
+    mov edx, offset clac_msg_neg_exponent_len
+    jmp clac_error
+
+    .section .rodata
+clac_msg_unknown:
+    .ascii "Unknown definition: "
+    .set clac_msg_unknown_len, . - clac_msg_unknown
+clac_msg_newline:
+    .ascii "\n"
+clac_msg_underflow:
+    .ascii "Stack underflow\n"
+    .set clac_msg_underflow_len, . - clac_msg_underflow
+clac_msg_overflow:
+    .ascii "Stack overflow\n"
+    .set clac_msg_overflow_len, . - clac_msg_overflow
+clac_msg_bad_index:
+    .ascii "Index out of bounds\n"
+    .set clac_msg_bad_index_len, . - clac_msg_bad_index
+clac_msg_bad_skip:
+    .ascii "Queue underflow\n"
+    .set clac_msg_bad_skip_len, . - clac_msg_bad_skip
+clac_msg_def_end:
+    .ascii "Unexpected definition end\n"
+    .set clac_msg_def_end_len, . - clac_msg_def_end
+clac_msg_div_zero:
+    .ascii "Division by zero\n"
+    .set clac_msg_div_zero_len, . - clac_msg_div_zero
+clac_msg_int_overflow:
+    .ascii "Overflow\n"
+    .set clac_msg_int_overflow_len, . - clac_msg_int_overflow
+clac_msg_neg_exponent:
+    .ascii "Negative exponent\n"
+    .set clac_msg_neg_exponent_len, . - clac_msg_neg_exponent
+
+    .bss
+    .balign 16
+clac_stack:
+    .skip 4 * STACK_SIZE
+"#;
+
+macro_rules! emit {
+    ($out: expr, $($arg:tt)*) => {
+        writeln!($out, $($arg)*).unwrap()
+    };
+}
+
+/// Compiled program, before it is assembled
+struct Program {
+    /// Word name -> index of its indirection cell
+    words: HashMap<String, usize>,
+    /// Number of compiled functions. Function 0 is the top-level code
+    functions: usize,
+    /// Code of the function being compiled
+    text: String,
+    /// Code of all finished functions
+    done: String,
+}
+
+impl Program {
+    fn new() -> Self {
+        Self {
+            words: HashMap::new(),
+            functions: 0,
+            text: String::new(),
+            done: String::new(),
+        }
+    }
+
+    fn word(&mut self, name: &str) -> usize {
+        let next = self.words.len();
+        *self.words.entry(name.to_string()).or_insert(next)
+    }
+
+    fn new_function(&mut self) -> usize {
+        self.functions += 1;
+        self.functions - 1
+    }
+
+    /// Make sure there are at least `n` values on the stack
+    fn need(&mut self, n: usize) {
+        emit!(self.text, "    lea rax, [r12 + {}]", 4 * n);
+        emit!(self.text, "    cmp rbx, rax");
+        emit!(self.text, "    jb clac_underflow");
+    }
+
+    /// Make sure there is room for one more value on the stack
+    fn room(&mut self) {
+        emit!(self.text, "    cmp rbx, r13");
+        emit!(self.text, "    jae clac_overflow");
+    }
+
+    /// Compile a token list into function `f`.
+    ///
+    /// At the top level (`def_name` is `None`), definitions are compiled into
+    /// separate functions, and bound to their names when control reaches them.
+    fn function(
+        &mut self,
+        f: usize,
+        tokens: &[Token],
+        def_name: Option<&str>,
+    ) -> Result<(), ClacError> {
+        use Token::*;
+
+        let outer = std::mem::take(&mut self.text);
+
+        let len = tokens.len();
+        let label = |i: usize| format!(".L{}_{}", f, i.min(len));
+
+        emit!(self.text, "clac_fn_{}:", f);
+
+        // The `;` of the definition being jumped over, at the top level
+        let mut closing = None;
+        let mut i = 0;
+        while i < len {
+            emit!(self.text, "{}:", label(i));
+            match &tokens[i] {
+                Num(x) => {
+                    self.room();
+                    emit!(self.text, "    mov dword ptr [rbx], {}", x);
+                    emit!(self.text, "    add rbx, 4");
+                }
+                Add => {
+                    self.need(2);
+                    emit!(self.text, "    sub rbx, 4");
+                    emit!(self.text, "    mov eax, [rbx]");
+                    emit!(self.text, "    add [rbx - 4], eax");
+                }
+                Sub => {
+                    self.need(2);
+                    emit!(self.text, "    sub rbx, 4");
+                    emit!(self.text, "    mov eax, [rbx]");
+                    emit!(self.text, "    sub [rbx - 4], eax");
+                }
+                Mul => {
+                    self.need(2);
+                    emit!(self.text, "    sub rbx, 4");
+                    emit!(self.text, "    mov eax, [rbx - 4]");
+                    emit!(self.text, "    imul eax, [rbx]");
+                    emit!(self.text, "    mov [rbx - 4], eax");
+                }
+                Div | Mod => {
+                    self.need(2);
+                    emit!(self.text, "    sub rbx, 4");
+                    emit!(self.text, "    mov ecx, [rbx]");
+                    emit!(self.text, "    mov eax, [rbx - 4]");
+                    emit!(self.text, "    test ecx, ecx");
+                    emit!(self.text, "    jz clac_div_zero");
+                    // i32::MIN / -1 does not fit
+                    emit!(self.text, "    cmp ecx, -1");
+                    emit!(self.text, "    jne 1f");
+                    emit!(self.text, "    cmp eax, 0x80000000");
+                    emit!(self.text, "    je clac_int_overflow");
+                    emit!(self.text, "1:");
+                    emit!(self.text, "    cdq");
+                    emit!(self.text, "    idiv ecx");
+                    let result = if tokens[i] == Div { "eax" } else { "edx" };
+                    emit!(self.text, "    mov [rbx - 4], {}", result);
+                }
+                Pow => {
+                    self.need(2);
+                    emit!(self.text, "    sub rbx, 4");
+                    emit!(self.text, "    mov ecx, [rbx]");
+                    emit!(self.text, "    mov eax, [rbx - 4]");
+                    emit!(self.text, "    test ecx, ecx");
+                    emit!(self.text, "    js clac_neg_exponent");
+                    emit!(self.text, "    call clac_pow");
+                    emit!(self.text, "    mov [rbx - 4], eax");
+                }
+                Less => {
+                    self.need(2);
+                    emit!(self.text, "    sub rbx, 4");
+                    emit!(self.text, "    mov eax, [rbx]");
+                    emit!(self.text, "    cmp [rbx - 4], eax");
+                    emit!(self.text, "    setl cl");
+                    emit!(self.text, "    movzx ecx, cl");
+                    emit!(self.text, "    mov [rbx - 4], ecx");
+                }
+                Swap => {
+                    self.need(2);
+                    emit!(self.text, "    mov eax, [rbx - 4]");
+                    emit!(self.text, "    mov ecx, [rbx - 8]");
+                    emit!(self.text, "    mov [rbx - 8], eax");
+                    emit!(self.text, "    mov [rbx - 4], ecx");
+                }
+                Rot => {
+                    // a b c rot => b c a
+                    self.need(3);
+                    emit!(self.text, "    mov eax, [rbx - 12]");
+                    emit!(self.text, "    mov ecx, [rbx - 8]");
+                    emit!(self.text, "    mov edx, [rbx - 4]");
+                    emit!(self.text, "    mov [rbx - 12], ecx");
+                    emit!(self.text, "    mov [rbx - 8], edx");
+                    emit!(self.text, "    mov [rbx - 4], eax");
+                }
+                Drop => {
+                    self.need(1);
+                    emit!(self.text, "    sub rbx, 4");
+                }
+                Pick => {
+                    // After popping n, the picked value is at rbx - 4n
+                    self.need(1);
+                    emit!(self.text, "    sub rbx, 4");
+                    emit!(self.text, "    movsxd rax, dword ptr [rbx]");
+                    emit!(self.text, "    test rax, rax");
+                    emit!(self.text, "    jle clac_bad_index");
+                    emit!(self.text, "    mov rcx, rbx");
+                    emit!(self.text, "    sub rcx, r12");
+                    emit!(self.text, "    shr rcx, 2");
+                    emit!(self.text, "    cmp rax, rcx");
+                    emit!(self.text, "    ja clac_bad_index");
+                    emit!(self.text, "    neg rax");
+                    emit!(self.text, "    mov eax, [rbx + rax * 4]");
+                    emit!(self.text, "    mov [rbx], eax");
+                    emit!(self.text, "    add rbx, 4");
+                }
+                If => {
+                    // cond if a b c
+                    self.need(1);
+                    emit!(self.text, "    sub rbx, 4");
+                    emit!(self.text, "    cmp dword ptr [rbx], 0");
+                    if i + 4 > len {
+                        emit!(self.text, "    je clac_bad_skip");
+                    } else {
+                        emit!(self.text, "    je {}", label(i + 4));
+                    }
+                }
+                Skip => {
+                    // n skip => jump to the (i + 1 + n)th token
+                    self.need(1);
+                    emit!(self.text, "    sub rbx, 4");
+                    emit!(self.text, "    movsxd rax, dword ptr [rbx]");
+                    emit!(self.text, "    cmp rax, {}", len - i - 1);
+                    // Unsigned compare also catches negative skips
+                    emit!(self.text, "    ja clac_bad_skip");
+                    emit!(self.text, "    lea rcx, [rip + .Ltable_{}]", f);
+                    emit!(self.text, "    jmp [rcx + rax * 8 + {}]", 8 * (i + 1));
+                }
+                Print => {
+                    self.need(1);
+                    emit!(self.text, "    sub rbx, 4");
+                    emit!(self.text, "    mov edi, [rbx]");
+                    emit!(self.text, "    call clac_print");
+                }
+                Quit => {
+                    emit!(self.text, "    jmp clac_quit");
+                }
+                DefBegin if def_name.is_none() => {
+                    let Some(end) = tokens[i..].iter().position(|t| *t == DefEnd) else {
+                        return Err(ClacError::QueueUnderflow);
+                    };
+                    let end = i + end;
+                    let def = &tokens[i + 1..end];
+                    match def.first() {
+                        None => return Err(ClacError::EmptyDefinition),
+                        Some(Custom(name)) if name == "comment" => {}
+                        Some(Custom(name)) => {
+                            let cell = self.word(name);
+                            let mut body = Queue::new();
+                            for token in &def[1..] {
+                                body.push(token.clone());
+                            }
+                            let body: Vec<Token> =
+                                crate::reach::prune(body, name).iter().cloned().collect();
+                            let g = self.new_function();
+                            self.function(g, &body, Some(name))?;
+                            emit!(self.text, "    lea rax, [rip + clac_fn_{}]", g);
+                            emit!(self.text, "    mov [rip + clac_cell_{}], rax", cell);
+                        }
+                        Some(_) => return Err(ClacError::InvalidDefinition),
+                    }
+                    // Skips landing inside the definition run the rest of it
+                    // as top-level code, up to its `;`, like the interpreter
+                    emit!(self.text, "    jmp {}", label(end + 1));
+                    closing = Some(end);
+                }
+                // Nested definition
+                DefBegin => return Err(ClacError::InvalidDefinition),
+                DefEnd if closing == Some(i) => {
+                    emit!(self.text, "    jmp clac_def_end");
+                    closing = None;
+                }
+                DefEnd => return Err(ClacError::UnexpectedDefinitionEnd),
+                Dup | Over | Nip | RotRot | PickPickLess(..) | IfSkip(_) => {
+                    return Err(ClacError::Unsupported(
+                        "superinstructions ahead of time".to_string(),
+                    ));
+                }
+                Custom(name) => {
+                    let cell = self.word(name);
+                    if i + 1 == len && def_name.is_some() {
+                        // Tail call
+                        emit!(self.text, "    jmp [rip + clac_cell_{}]", cell);
+                    } else {
+                        emit!(self.text, "    call [rip + clac_cell_{}]", cell);
+                    }
+                }
+            }
+            i += 1;
+        }
+        emit!(self.text, "{}:", label(len));
+        emit!(self.text, "    ret");
+
+        // Address table for dynamic skips
+        emit!(self.text, "    .section .rodata");
+        emit!(self.text, "    .balign 8");
+        emit!(self.text, ".Ltable_{}:", f);
+        for j in 0..=len {
+            emit!(self.text, "    .quad {}", label(j));
+        }
+        emit!(self.text, "    .text");
+
+        let text = std::mem::replace(&mut self.text, outer);
+        self.done.push_str(&text);
+        Ok(())
+    }
+
+    fn finish(self) -> String {
+        let mut out = String::new();
+        emit!(out, "    .intel_syntax noprefix");
+        emit!(out, "    .set STACK_SIZE, {}", STACK_SIZE);
+        emit!(out, "    .text");
+        out.push_str(&self.done);
+
+        let mut words: Vec<_> = self.words.into_iter().collect();
+        words.sort_by_key(|(_, cell)| *cell);
+
+        // Words start unbound, reporting themselves as unknown
+        for (name, cell) in &words {
+            emit!(out, "clac_unbound_{}:", cell);
+            emit!(out, "    lea rsi, [rip + clac_name_{}]", cell);
+            emit!(out, "    mov edx, {}", name.len());
+            emit!(out, "    jmp clac_unknown");
+        }
+        emit!(out, "    .data");
+        emit!(out, "    .balign 8");
+        for (_, cell) in &words {
+            emit!(out, "clac_cell_{}:", cell);
+            emit!(out, "    .quad clac_unbound_{}", cell);
+        }
+        emit!(out, "    .section .rodata");
+        for (name, cell) in &words {
+            let bytes: Vec<_> = name.bytes().map(|b| b.to_string()).collect();
+            emit!(out, "clac_name_{}:", cell);
+            emit!(out, "    .byte {}", bytes.join(", "));
+        }
+
+        out.push_str(RUNTIME);
+        out
+    }
+}
+
+/// Compile a whole program into assembly (Intel syntax, GNU as). Programs
+/// that would fail to parse when run, and superinstructions, are errors.
+pub fn compile(queue: Queue<Token>) -> Result<String, ClacError> {
+    let tokens: Vec<Token> = queue.iter().cloned().collect();
+
+    let mut program = Program::new();
+    let top = program.new_function();
+    program.function(top, &tokens, None)?;
+    Ok(program.finish())
+}
+
+/// Compile a whole program into a static executable at `output`.
+///
+/// Needs `as` and `ld` from binutils. Errors from `compile` are wrapped in
+/// the returned `io::Error`.
+pub fn build(queue: Queue<Token>, output: &Path) -> std::io::Result<()> {
+    let asm = compile(queue).map_err(std::io::Error::other)?;
+
+    // A directory of its own, so that builds running at once do not share it
+    static BUILDS: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "clacjit-{}-{}",
+        std::process::id(),
+        BUILDS.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::create_dir_all(&dir)?;
+    let asm_path = dir.join("program.s");
+    let obj_path = dir.join("program.o");
+    std::fs::write(&asm_path, asm)?;
+
+    let result = run(Command::new("as").arg("-o").arg(&obj_path).arg(&asm_path)).and_then(|_| {
+        run(Command::new("ld")
+            .arg("-static")
+            .arg("-o")
+            .arg(output)
+            .arg(&obj_path))
+    });
+
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+fn run(command: &mut Command) -> std::io::Result<()> {
+    let status = command.status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!(
+            "{:?} failed: {}",
+            command, status
+        )))
+    }
+}
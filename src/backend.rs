@@ -0,0 +1,92 @@
+//! Execution backends: how definitions and top-level code are run.
+//!
+//! `eval` parses definitions and interprets top-level tokens itself, and
+//! hands everything else to the backend the `State` was built with.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::native::Native;
+use crate::{ClacError, Queue, State, Token};
+
+/// Backends are `Send`, so that a `State` can be moved to another thread
+pub trait ExecutionBackend: Send {
+    /// Bind a definition, replacing any previous one by that name
+    fn define(&mut self, state: &mut State, name: &str, def: Queue<Token>);
+
+    /// Bind a word implemented by the host, replacing any previous one
+    fn define_native(&mut self, state: &mut State, name: &str, native: Arc<Native>);
+
+    /// Run a definition, or return `false` if there is none by that name.
+    /// The backend may also just set the state up for `eval` to run it.
+    fn call(&self, state: &mut State, name: &str) -> Result<bool, ClacError>;
+
+    /// Do work queued while `call` ran that needs the backend to itself,
+    /// like recompiling hot definitions. `eval` calls it after each call.
+    fn after_call(&mut self, _state: &mut State) {}
+
+    /// Run the top-level code at the front of the queue, up to the next
+    /// definition. Backends that return `false` have it interpreted.
+    fn run_chunk(&mut self, _state: &mut State) -> Result<bool, ClacError> {
+        Ok(false)
+    }
+
+    /// Names of the definitions bound so far
+    fn definitions(&self, state: &State) -> Vec<String>;
+
+    /// Tokens of a definition, as they were bound, or `None` for natives
+    /// and unknown words
+    fn body(&self, state: &State, name: &str) -> Option<Vec<Token>>;
+}
+
+/// Runs definitions token by token, from the queue
+#[derive(Default)]
+pub struct InterpreterBackend {
+    defs: HashMap<String, Arc<Queue<Token>>>,
+    natives: HashMap<String, Arc<Native>>,
+}
+
+impl ExecutionBackend for InterpreterBackend {
+    fn define(&mut self, state: &mut State, name: &str, def: Queue<Token>) {
+        if let Some(profile) = &mut state.profile {
+            profile.reset(name);
+        }
+        self.natives.remove(name);
+        self.defs.insert(name.to_string(), Arc::new(def));
+    }
+
+    fn define_native(&mut self, _state: &mut State, name: &str, native: Arc<Native>) {
+        self.defs.remove(name);
+        self.natives.insert(name.to_string(), native);
+    }
+
+    fn call(&self, state: &mut State, name: &str) -> Result<bool, ClacError> {
+        let Some(def) = self.defs.get(name) else {
+            return match self.natives.get(name) {
+                Some(native) => native.call(&mut state.stack).map(|()| true),
+                None => Ok(false),
+            };
+        };
+        state.check_return_stack(state.return_stack.len())?;
+        // Move the queue to the return stack
+        state.return_stack.push(state.queue.take());
+        state.queue.become_def(def);
+        state.frames.push(name.to_string());
+        if let Some(profile) = &mut state.profile {
+            profile.word_mut(name).calls += 1;
+        }
+        Ok(true)
+    }
+
+    fn definitions(&self, _state: &State) -> Vec<String> {
+        self.defs
+            .keys()
+            .chain(self.natives.keys())
+            .cloned()
+            .collect()
+    }
+
+    fn body(&self, _state: &State, name: &str) -> Option<Vec<Token>> {
+        self.defs.get(name).map(|def| def.iter().cloned().collect())
+    }
+}
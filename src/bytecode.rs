@@ -0,0 +1,428 @@
+//! Bytecode backend.
+//!
+//! Definitions and top-level chunks are compiled to flat lists of ops, with
+//! calls bound through a table of words, and run by a loop with its own
+//! frame stack. Jumps name token slots, like `skip` and `if` count them, and
+//! go through a table from slots to ops.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::backend::ExecutionBackend;
+use crate::native::Native;
+use crate::{peephole, ClacError, Position, Queue, State, Token};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    Push(i32),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Less,
+    Swap,
+    Rot,
+    Pick,
+    Drop,
+    Print,
+    Quit,
+    Dup,
+    Over,
+    Nip,
+    RotRot,
+    PickPickLess(i32, i32),
+    /// Pop, and go to a slot if zero
+    JumpIfZero(usize),
+    /// Pop, and go to a slot if not zero
+    JumpIfNonZero(usize),
+    /// Go to a slot
+    Jump(usize),
+    /// Pop `n`, and go `n` slots past the slot after this one
+    Skip(usize),
+    /// Call a word by its index
+    Call(usize),
+}
+
+pub struct Function {
+    /// The definition, if it is not a top-level chunk
+    name: Option<String>,
+    /// Tokens it was compiled from
+    body: Vec<Token>,
+    ops: Vec<Op>,
+    /// Index of the op for each slot, and of the end
+    positions: Vec<usize>,
+}
+
+impl Function {
+    /// Number of token slots
+    fn len(&self) -> usize {
+        self.positions.len() - 1
+    }
+
+    /// Position of the first token the op at `pc` was compiled from
+    fn position(&self, pc: usize) -> Position {
+        match &self.name {
+            Some(name) => {
+                let slot = self.positions.iter().position(|op| *op == pc).unwrap();
+                Position::Word(name.clone(), slot)
+            }
+            None => Position::TopLevel,
+        }
+    }
+}
+
+/// Whether every `skip` count is the constant right before it
+fn constant_skips(tokens: &[Token]) -> bool {
+    use Token::*;
+
+    let mut targets = HashSet::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            If => {
+                targets.insert(i + 4);
+            }
+            IfSkip(n) => targets.extend([i + 4, i + 3 + *n as usize]),
+            Skip => match i.checked_sub(1).map(|j| &tokens[j]) {
+                Some(Num(n)) if *n >= 0 => {
+                    targets.insert(i + 1 + *n as usize);
+                }
+                _ => return false,
+            },
+            _ => {}
+        }
+    }
+    // Landing right on a skip makes its count dynamic
+    !tokens
+        .iter()
+        .enumerate()
+        .any(|(i, token)| *token == Skip && targets.contains(&i))
+}
+
+#[derive(Clone)]
+enum Word {
+    Clac(Arc<Function>),
+    Native(Arc<Native>),
+}
+
+/// A call frame: the function, where it continues, and whether it is a
+/// top-level chunk
+struct Frame {
+    function: Arc<Function>,
+    pc: usize,
+    top_level: bool,
+}
+
+#[derive(Default)]
+pub struct BytecodeBackend {
+    /// Index of each word
+    ids: HashMap<String, usize>,
+    names: Vec<String>,
+    words: Vec<Option<Word>>,
+}
+
+impl BytecodeBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn id(&mut self, name: &str) -> usize {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = self.names.len();
+        self.ids.insert(name.to_string(), id);
+        self.names.push(name.to_string());
+        self.words.push(None);
+        id
+    }
+
+    pub fn compile(&mut self, queue: &Queue<Token>) -> Function {
+        use Token::*;
+
+        let tokens: Vec<Token> = queue.iter().cloned().collect();
+        let constant = constant_skips(&tokens);
+
+        let mut ops = vec![];
+        let mut positions = vec![];
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            let mut width = token.width();
+            let op = match (token, tokens.get(i + 1)) {
+                (Num(n), Some(Skip)) if constant && *n >= 0 => {
+                    width = 2;
+                    Op::Jump(i + 2 + *n as usize)
+                }
+                (Num(n), _) => Op::Push(*n),
+                (Add, _) => Op::Add,
+                (Sub, _) => Op::Sub,
+                (Mul, _) => Op::Mul,
+                (Div, _) => Op::Div,
+                (Mod, _) => Op::Mod,
+                (Pow, _) => Op::Pow,
+                (Less, _) => Op::Less,
+                (Swap, _) => Op::Swap,
+                (Rot, _) => Op::Rot,
+                (Pick, _) => Op::Pick,
+                (Drop, _) => Op::Drop,
+                (Print, _) => Op::Print,
+                (Quit, _) => Op::Quit,
+                (Dup, _) => Op::Dup,
+                (Over, _) => Op::Over,
+                (Nip, _) => Op::Nip,
+                (RotRot, _) => Op::RotRot,
+                (PickPickLess(a, b), _) => Op::PickPickLess(*a, *b),
+                (If, _) => Op::JumpIfZero(i + 4),
+                (IfSkip(n), _) => Op::JumpIfNonZero(i + 3 + *n as usize),
+                (Skip, _) => Op::Skip(i),
+                (Custom(name), _) => Op::Call(self.id(name)),
+                (DefBegin | DefEnd, _) => panic!("Can not compile definition tokens"),
+            };
+            for _ in 0..width {
+                positions.push(ops.len());
+            }
+            ops.push(op);
+            i += width;
+        }
+        positions.push(ops.len());
+
+        Function {
+            name: None,
+            body: tokens,
+            ops,
+            positions,
+        }
+    }
+
+    fn run(
+        &self,
+        state: &mut State,
+        function: Arc<Function>,
+        top_level: bool,
+    ) -> Result<(), ClacError> {
+        let mut frames: Vec<Frame> = vec![];
+        let mut frame = Frame {
+            function,
+            pc: 0,
+            top_level,
+        };
+
+        loop {
+            let Some(op) = frame.function.ops.get(frame.pc) else {
+                match frames.pop() {
+                    Some(caller) => {
+                        frame = caller;
+                        continue;
+                    }
+                    None => return Ok(()),
+                }
+            };
+            state.burn(|_| frame.function.position(frame.pc))?;
+            frame.pc += 1;
+
+            // Slot to go to, if any
+            let mut target = None;
+            match op {
+                Op::Push(n) => state.stack.push(*n),
+                Op::Add => {
+                    let a = state.must_pop()?;
+                    let b = state.must_pop()?;
+                    state.stack.push(a + b);
+                }
+                Op::Sub => {
+                    let a = state.must_pop()?;
+                    let b = state.must_pop()?;
+                    state.stack.push(b - a);
+                }
+                Op::Mul => {
+                    let a = state.must_pop()?;
+                    let b = state.must_pop()?;
+                    state.stack.push(a * b);
+                }
+                Op::Div | Op::Mod => {
+                    let a = state.must_pop()?;
+                    let b = state.must_pop()?;
+                    if a == 0 {
+                        return Err(ClacError::DivisionByZero);
+                    }
+                    if a == -1 && b == i32::MIN {
+                        return Err(ClacError::Overflow);
+                    }
+                    state.stack.push(if *op == Op::Div { b / a } else { b % a });
+                }
+                Op::Pow => {
+                    let a = state.must_pop()?;
+                    let b = state.must_pop()?;
+                    if a < 0 {
+                        return Err(ClacError::NegativeExponent);
+                    }
+                    state.stack.push(b.pow(a as u32));
+                }
+                Op::Less => {
+                    let a = state.must_pop()?;
+                    let b = state.must_pop()?;
+                    state.stack.push(if b < a { 1 } else { 0 });
+                }
+                Op::Swap => {
+                    let a = state.must_pop()?;
+                    let b = state.must_pop()?;
+                    state.stack.push(a);
+                    state.stack.push(b);
+                }
+                Op::Rot => {
+                    let a = state.must_pop()?;
+                    let b = state.must_pop()?;
+                    let c = state.must_pop()?;
+                    state.stack.push(b);
+                    state.stack.push(a);
+                    state.stack.push(c);
+                }
+                Op::Pick => {
+                    let n = state.must_pop()?;
+                    if n <= 0 {
+                        return Err(ClacError::InvalidIndex);
+                    }
+                    state.stack.push(state.must_pick(n as usize)?);
+                }
+                Op::Drop => {
+                    state.must_pop()?;
+                }
+                Op::Print => {
+                    let n = state.must_pop()?;
+                    state.print(n)?;
+                }
+                Op::Quit => {
+                    state.quit = true;
+                    return Ok(());
+                }
+                Op::Dup => state.stack.push(state.must_pick(1)?),
+                Op::Over => state.stack.push(state.must_pick(2)?),
+                Op::Nip => {
+                    let a = state.must_pop()?;
+                    state.must_pop()?;
+                    state.stack.push(a);
+                }
+                Op::RotRot => {
+                    // a b c rot rot => c a b
+                    let c = state.must_pop()?;
+                    let b = state.must_pop()?;
+                    let a = state.must_pop()?;
+                    state.stack.push(c);
+                    state.stack.push(a);
+                    state.stack.push(b);
+                }
+                Op::PickPickLess(a, b) => {
+                    let x = state.must_pick(*a as usize)?;
+                    state.stack.push(x);
+                    let y = state.must_pick(*b as usize)?;
+                    state.must_pop()?;
+                    state.stack.push(if x < y { 1 } else { 0 });
+                }
+                Op::JumpIfZero(slot) => {
+                    if state.must_pop()? == 0 {
+                        target = Some(*slot);
+                    }
+                }
+                Op::JumpIfNonZero(slot) => {
+                    if state.must_pop()? != 0 {
+                        target = Some(*slot);
+                    }
+                }
+                Op::Jump(slot) => target = Some(*slot),
+                Op::Skip(slot) => {
+                    let n = state.must_pop()?;
+                    if n < 0 {
+                        return Err(ClacError::NegativeSkip);
+                    }
+                    target = Some(slot + 1 + n as usize);
+                }
+                Op::Call(id) => {
+                    let function = match &self.words[*id] {
+                        Some(Word::Clac(function)) => function,
+                        Some(Word::Native(native)) => {
+                            native.call(&mut state.stack)?;
+                            state.check_stack()?;
+                            continue;
+                        }
+                        None => return Err(ClacError::UnknownDefinition(self.names[*id].clone())),
+                    };
+                    let callee = Frame {
+                        function: function.clone(),
+                        pc: 0,
+                        top_level: false,
+                    };
+                    // Nothing is left to do in a frame that ends with a call
+                    let caller = std::mem::replace(&mut frame, callee);
+                    if caller.pc < caller.function.ops.len() {
+                        state.check_return_stack(frames.len())?;
+                        frames.push(caller);
+                    }
+                }
+            }
+
+            state.check_stack()?;
+
+            if let Some(slot) = target {
+                let len = frame.function.len();
+                if slot <= len {
+                    frame.pc = frame.function.positions[slot];
+                } else if frame.top_level && frames.is_empty() {
+                    // Let the interpreter skip the rest of the top-level queue
+                    state.pending_skip = slot - len;
+                    return Ok(());
+                } else {
+                    return Err(ClacError::QueueUnderflow);
+                }
+            }
+        }
+    }
+}
+
+impl ExecutionBackend for BytecodeBackend {
+    fn define(&mut self, _state: &mut State, name: &str, def: Queue<Token>) {
+        let mut function = self.compile(&def);
+        function.name = Some(name.to_string());
+        let id = self.id(name);
+        self.words[id] = Some(Word::Clac(Arc::new(function)));
+    }
+
+    fn define_native(&mut self, _state: &mut State, name: &str, native: Arc<Native>) {
+        let id = self.id(name);
+        self.words[id] = Some(Word::Native(native));
+    }
+
+    fn call(&self, state: &mut State, name: &str) -> Result<bool, ClacError> {
+        let Some(Some(word)) = self.ids.get(name).map(|id| &self.words[*id]) else {
+            return Ok(false);
+        };
+        match word.clone() {
+            Word::Clac(function) => self.run(state, function, false)?,
+            Word::Native(native) => native.call(&mut state.stack)?,
+        }
+        Ok(true)
+    }
+
+    fn run_chunk(&mut self, state: &mut State) -> Result<bool, ClacError> {
+        let chunk = peephole::optimize(state.take_chunk());
+        let function = self.compile(&chunk);
+        self.run(state, Arc::new(function), true)?;
+        Ok(true)
+    }
+
+    fn definitions(&self, _state: &State) -> Vec<String> {
+        self.ids
+            .iter()
+            .filter(|(_, id)| self.words[**id].is_some())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    fn body(&self, _state: &State, name: &str) -> Option<Vec<Token>> {
+        match self.ids.get(name).map(|id| &self.words[*id]) {
+            Some(Some(Word::Clac(function))) => Some(function.body.clone()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Compiled bytecode files (`.clacb`).
+//!
+//! A file holds the parsed program, so that it does not have to be parsed
+//! again: the names it uses, interned once, then its definitions and its
+//! top-level code, in order. All numbers are little-endian.
+//!
+//! ```text
+//! "CLACB\0\0\0" version:u32
+//! symbols:u32 { len:u32 utf8 }*
+//! items:u32 { 0 tokens | 1 name:u32 tokens }*
+//! tokens = count:u32 { tag:u8 [i32 for numbers | symbol:u32 for words] }*
+//! ```
+//!
+//! Loading checks everything, so malformed files are errors, not panics.
+
+use std::collections::HashMap;
+
+use crate::{parse, ClacError, Queue, State, Token};
+
+const MAGIC: &[u8; 8] = b"CLACB\0\0\0";
+
+/// Bump this whenever the format changes
+const VERSION: u32 = 1;
+
+const CODE: u8 = 0;
+const DEF: u8 = 1;
+
+/// Tag of each token that can be stored. Definition markers are implied by
+/// items, and superinstructions only exist in optimized queues, which are
+/// not stored.
+fn tag(token: &Token) -> Result<u8, ClacError> {
+    use Token::*;
+    Ok(match token {
+        Num(_) => 0,
+        Add => 1,
+        Sub => 2,
+        Mul => 3,
+        Div => 4,
+        Mod => 5,
+        Pow => 6,
+        Less => 7,
+        If => 8,
+        Skip => 9,
+        Print => 10,
+        Quit => 11,
+        Swap => 12,
+        Rot => 13,
+        Pick => 14,
+        Drop => 15,
+        Custom(_) => 16,
+        DefBegin | DefEnd => unreachable!("definitions are written as items"),
+        Dup | Over | Nip | RotRot | PickPickLess(..) | IfSkip(_) => {
+            return Err(ClacError::Unsupported(
+                "superinstructions in bytecode files".to_string(),
+            ))
+        }
+    })
+}
+
+fn untag(tag: u8) -> Option<Token> {
+    use Token::*;
+    Some(match tag {
+        1 => Add,
+        2 => Sub,
+        3 => Mul,
+        4 => Div,
+        5 => Mod,
+        6 => Pow,
+        7 => Less,
+        8 => If,
+        9 => Skip,
+        10 => Print,
+        11 => Quit,
+        12 => Swap,
+        13 => Rot,
+        14 => Pick,
+        15 => Drop,
+        _ => return None,
+    })
+}
+
+fn write_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+/// Items, with the symbols they use
+#[derive(Default)]
+struct Writer {
+    out: Vec<u8>,
+    ids: HashMap<String, usize>,
+    symbols: Vec<String>,
+}
+
+impl Writer {
+    fn symbol(&mut self, name: &str) {
+        let id = match self.ids.get(name) {
+            Some(id) => *id,
+            None => {
+                let id = self.symbols.len();
+                self.ids.insert(name.to_string(), id);
+                self.symbols.push(name.to_string());
+                id
+            }
+        };
+        write_u32(&mut self.out, id);
+    }
+
+    fn tokens(&mut self, tokens: &[Token]) -> Result<(), ClacError> {
+        write_u32(&mut self.out, tokens.len());
+        for token in tokens {
+            self.out.push(tag(token)?);
+            match token {
+                Token::Num(n) => self.out.extend_from_slice(&n.to_le_bytes()),
+                Token::Custom(name) => self.symbol(name),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write a parsed program. Definitions that would fail to parse when run
+/// are errors here, and so are superinstructions from `peephole::optimize`.
+pub fn write(queue: &Queue<Token>) -> Result<Vec<u8>, ClacError> {
+    let mut items = Writer::default();
+    let mut count = 0;
+    let mut tokens = queue.iter().peekable();
+    while tokens.peek().is_some() {
+        count += 1;
+        let mut code = vec![];
+        while let Some(token) = tokens.next_if(|t| !matches!(t, Token::DefBegin | Token::DefEnd)) {
+            code.push(token.clone());
+        }
+        if !code.is_empty() {
+            items.out.push(CODE);
+            items.tokens(&code)?;
+            continue;
+        }
+
+        if tokens.next() == Some(&Token::DefEnd) {
+            return Err(ClacError::UnexpectedDefinitionEnd);
+        }
+        let mut def = vec![];
+        loop {
+            match tokens.next() {
+                None => return Err(ClacError::QueueUnderflow),
+                Some(Token::DefEnd) => break,
+                Some(token) => def.push(token.clone()),
+            }
+        }
+        let name = match def.first() {
+            None => return Err(ClacError::EmptyDefinition),
+            Some(Token::Custom(name)) => name.clone(),
+            Some(_) => return Err(ClacError::InvalidDefinition),
+        };
+        if def.contains(&Token::DefBegin) {
+            return Err(ClacError::InvalidDefinition);
+        }
+        items.out.push(DEF);
+        items.symbol(&name);
+        items.tokens(&def[1..])?;
+    }
+
+    let mut out = MAGIC.to_vec();
+    write_u32(&mut out, VERSION as usize);
+    write_u32(&mut out, items.symbols.len());
+    for name in &items.symbols {
+        write_u32(&mut out, name.len());
+        out.extend_from_slice(name.as_bytes());
+    }
+    write_u32(&mut out, count);
+    out.extend_from_slice(&items.out);
+    Ok(out)
+}
+
+fn invalid(message: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid bytecode file: {}", message),
+    )
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> std::io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid("unexpected end"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> std::io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// A count of items at least `size` bytes each, checked against what
+    /// is left, so that huge counts do not allocate
+    fn count(&mut self, size: usize) -> std::io::Result<usize> {
+        let count = self.u32()? as usize;
+        if count.saturating_mul(size) > self.0.len() {
+            return Err(invalid("count past the end"));
+        }
+        Ok(count)
+    }
+
+    fn tokens(&mut self, symbols: &[String], queue: &mut Queue<Token>) -> std::io::Result<()> {
+        for _ in 0..self.count(1)? {
+            let token = match self.u8()? {
+                0 => Token::Num(i32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+                16 => Token::Custom(self.symbol(symbols)?),
+                tag => untag(tag).ok_or_else(|| invalid(format!("unknown token {}", tag)))?,
+            };
+            queue.push(token);
+        }
+        Ok(())
+    }
+
+    fn symbol(&mut self, symbols: &[String]) -> std::io::Result<String> {
+        let id = self.u32()? as usize;
+        symbols
+            .get(id)
+            .cloned()
+            .ok_or_else(|| invalid(format!("unknown symbol {}", id)))
+    }
+}
+
+/// Read a program written by `write`, as the tokens `State::parse` would
+/// have queued
+pub fn read(bytes: &[u8]) -> std::io::Result<Queue<Token>> {
+    let mut reader = Reader(bytes);
+    if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+        return Err(invalid("not a bytecode file"));
+    }
+    let version = reader.u32()?;
+    if version != VERSION {
+        return Err(invalid(format!(
+            "version {}, expected {}",
+            version, VERSION
+        )));
+    }
+
+    let mut symbols = vec![];
+    for _ in 0..reader.count(4)? {
+        let len = reader.u32()? as usize;
+        let name = std::str::from_utf8(reader.take(len)?).map_err(invalid)?;
+        // Names must read back as words
+        let mut parsed = parse(name);
+        if !matches!(parsed.pop(), Some(Token::Custom(_))) || !parsed.is_empty() {
+            return Err(invalid(format!("bad word name {:?}", name)));
+        }
+        symbols.push(name.to_string());
+    }
+
+    let mut queue = Queue::new();
+    for _ in 0..reader.count(1)? {
+        match reader.u8()? {
+            CODE => reader.tokens(&symbols, &mut queue)?,
+            DEF => {
+                queue.push(Token::DefBegin);
+                queue.push(Token::Custom(reader.symbol(&symbols)?));
+                reader.tokens(&symbols, &mut queue)?;
+                queue.push(Token::DefEnd);
+            }
+            kind => return Err(invalid(format!("unknown item {}", kind))),
+        }
+    }
+    if !reader.0.is_empty() {
+        return Err(invalid("trailing bytes"));
+    }
+    Ok(queue)
+}
+
+impl State {
+    /// Queue a program from a bytecode file, like `parse` does for source
+    pub fn load_bytecode(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let mut program = read(bytes)?;
+        while let Some(token) = program.pop() {
+            self.queue.push(token);
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,15 @@
 use std::collections::VecDeque;
+use std::sync::Arc;
 
 // pub struct Stack<T>(LinkedList<T>);
 pub struct Stack<T>(Vec<T>);
 
+impl<T> Default for Stack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Stack<T> {
     pub fn new() -> Self {
         Self(Vec::new())
@@ -27,17 +34,60 @@ impl<T> Stack<T> {
     /// Pick the nth element from the top of the stack
     pub fn pick(&self, n: usize) -> Option<&T> {
         // self.0.iter().rev().nth(n)
-        self.0.get(self.0.len() - n - 1)
+        self.0.get(self.0.len().checked_sub(n)?.checked_sub(1)?)
+    }
+
+    /// # Safety
+    /// The stack must not be empty
+    pub unsafe fn pop_unchecked(&mut self) -> T {
+        self.0.pop().unwrap_unchecked()
+    }
+
+    /// # Safety
+    /// The stack must have more than `n` elements
+    pub unsafe fn pick_unchecked(&self, n: usize) -> &T {
+        self.0.get_unchecked(self.0.len() - n - 1)
     }
 
     pub fn iter(&self) -> std::slice::Iter<'_, T> {
         self.0.iter()
     }
+
+    /// Elements from the bottom to the top
+    pub fn as_slice(&self) -> &[T] {
+        &self.0
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl<T: Copy + Default> Stack<T> {
+    /// Replace the top `n` elements by `m` elements, written through the
+    /// returned pointer. The old elements can still be read through it.
+    pub fn window(&mut self, n: usize, m: usize) -> Option<*mut T> {
+        let base = self.0.len().checked_sub(n)?;
+        if m > n {
+            self.0.resize(base + m, T::default());
+        }
+        let pointer = unsafe { self.0.as_mut_ptr().add(base) };
+        if m < n {
+            self.0.truncate(base + m);
+        }
+        Some(pointer)
+    }
 }
 
 // pub struct Queue<T>(LinkedList<T>);
 pub struct Queue<T>(VecDeque<T>);
 
+impl<T> Default for Queue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Queue<T> {
     pub fn new() -> Self {
         Self(VecDeque::new())
@@ -48,6 +98,9 @@ impl<T> Queue<T> {
     pub fn pop(&mut self) -> Option<T> {
         self.0.pop_front()
     }
+    pub fn peek(&self) -> Option<&T> {
+        self.0.front()
+    }
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
@@ -56,17 +109,29 @@ impl<T> Queue<T> {
         self.0.len()
     }
 
-    pub fn iter(&self) -> std::collections::vec_deque::Iter<T> {
+    pub fn get(&self, i: usize) -> Option<&T> {
+        self.0.get(i)
+    }
+
+    pub fn iter(&self) -> std::collections::vec_deque::Iter<'_, T> {
         self.0.iter()
     }
 }
 
 pub enum TheQueue {
     Real(Queue<Token>),
-    Iter(std::collections::vec_deque::Iter<'static, Token>),
+    /// A definition being run, and the index of its next token. Shared with
+    /// the definitions map, so that calls do not copy the body.
+    Def(Arc<Queue<Token>>, usize),
     None, // This should only be temp
 }
 
+impl Default for TheQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TheQueue {
     pub fn new() -> Self {
         Self::Real(Queue::new())
@@ -82,18 +147,44 @@ impl TheQueue {
     pub fn pop(&mut self) -> Option<Token> {
         match self {
             Self::Real(queue) => queue.pop(),
-            Self::Iter(iter) => {
-                let token = iter.next();
-                token.cloned()
+            Self::Def(def, next) => {
+                let token = def.get(*next).cloned();
+                *next += token.is_some() as usize;
+                token
             }
             Self::None => unreachable!(),
         }
     }
 
+    pub fn peek(&self) -> Option<&Token> {
+        match self {
+            Self::Real(queue) => queue.peek(),
+            Self::Def(def, next) => def.get(*next),
+            Self::None => unreachable!(),
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
         match self {
             Self::Real(queue) => queue.is_empty(),
-            Self::Iter(iter) => iter.len() == 0,
+            Self::Def(def, next) => *next == def.len(),
+            Self::None => unreachable!(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Real(queue) => queue.len(),
+            Self::Def(def, next) => def.len() - *next,
+            Self::None => unreachable!(),
+        }
+    }
+
+    /// Tokens left, front first
+    pub fn iter(&self) -> std::collections::vec_deque::Iter<'_, Token> {
+        match self {
+            Self::Real(queue) => queue.iter(),
+            Self::Def(def, next) => def.0.range(*next..),
             Self::None => unreachable!(),
         }
     }
@@ -109,11 +200,17 @@ impl TheQueue {
         }
     }
 
-    pub fn become_iter(&mut self, queue: &Queue<Token>) {
-        let _ = std::mem::replace(
-            self,
-            Self::Iter(unsafe { std::mem::transmute(queue.iter()) }),
-        );
+    /// Token `i` of the definition being run, counting from its start
+    pub fn def_token(&self, i: usize) -> Option<&Token> {
+        match self {
+            Self::Def(def, _) => def.get(i),
+            _ => None,
+        }
+    }
+
+    /// Run a definition from its first token
+    pub fn become_def(&mut self, def: &Arc<Queue<Token>>) {
+        *self = Self::Def(def.clone(), 0);
     }
 }
 
@@ -145,6 +242,62 @@ pub enum Token {
     Drop,  // drop
 
     Custom(String),
+
+    // Superinstructions, see `peephole`
+    Dup,                    // 1 pick
+    Over,                   // 2 pick
+    Nip,                    // swap drop
+    RotRot,                 // rot rot
+    PickPickLess(i32, i32), // a pick b pick <
+    IfSkip(i32),            // if n skip _
+}
+
+impl Token {
+    /// Number of queue slots a token covers. Superinstructions stay in the
+    /// slot of the first token they replace, and cover the rest.
+    pub fn width(&self) -> usize {
+        use Token::*;
+        match self {
+            Dup | Over | Nip | RotRot => 2,
+            PickPickLess(..) => 5,
+            IfSkip(_) => 4,
+            _ => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Token::*;
+        match self {
+            Num(num) => write!(f, "{}", num),
+            Add => write!(f, "+"),
+            Sub => write!(f, "-"),
+            Mul => write!(f, "*"),
+            Div => write!(f, "/"),
+            Mod => write!(f, "%"),
+            Pow => write!(f, "**"),
+            Less => write!(f, "<"),
+            DefBegin => write!(f, ":"),
+            DefEnd => write!(f, ";"),
+            If => write!(f, "if"),
+            Skip => write!(f, "skip"),
+            Print => write!(f, "print"),
+            Quit => write!(f, "quit"),
+            Swap => write!(f, "swap"),
+            Rot => write!(f, "rot"),
+            Pick => write!(f, "pick"),
+            Drop => write!(f, "drop"),
+            Custom(name) => write!(f, "{}", name),
+            // Superinstructions show the token they replaced
+            Dup => write!(f, "1"),
+            Over => write!(f, "2"),
+            Nip => write!(f, "swap"),
+            RotRot => write!(f, "rot"),
+            PickPickLess(a, _) => write!(f, "{}", a),
+            IfSkip(_) => write!(f, "if"),
+        }
+    }
 }
 
 // Custom abbrs
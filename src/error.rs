@@ -0,0 +1,82 @@
+use std::fmt;
+
+/// Everything that can stop a clac program
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClacError {
+    StackUnderflow,
+    /// The data stack went over the limit set with `State::set_max_stack`
+    StackOverflow,
+    /// Calls nested deeper than `State::set_max_return_stack` allows
+    ReturnStackOverflow,
+    QueueUnderflow,
+    IndexOutOfBounds,
+    InvalidIndex,
+    DivisionByZero,
+    Overflow,
+    NegativeExponent,
+    NegativeSkip,
+    /// Jitted calls nested too deep, with `Checks::Full`
+    RecursionTooDeep,
+    UnknownDefinition(String),
+    EmptyDefinition,
+    InvalidDefinition,
+    UnexpectedDefinitionEnd,
+    /// Writing to the output failed
+    Output(String),
+    /// Reported by a native word
+    Native(String),
+    /// Something a tool can not handle, such as superinstructions in a
+    /// queue compiled ahead of time
+    Unsupported(String),
+    /// The instruction budget set with `State::set_fuel` ran out
+    FuelExhausted(Position),
+    /// The flag from `State::interrupt_handle` was set
+    Interrupted(Position),
+}
+
+/// Where a program was when it was stopped
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Position {
+    /// In top-level code
+    TopLevel,
+    /// At a token of a definition, counted from 0
+    Word(String, usize),
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Position::TopLevel => write!(f, "at top level"),
+            Position::Word(name, pos) => write!(f, "in {} at token {}", name, pos),
+        }
+    }
+}
+
+impl fmt::Display for ClacError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClacError::StackUnderflow => write!(f, "Stack underflow"),
+            ClacError::StackOverflow => write!(f, "Stack overflow"),
+            ClacError::ReturnStackOverflow => write!(f, "Return stack overflow"),
+            ClacError::QueueUnderflow => write!(f, "Queue underflow"),
+            ClacError::IndexOutOfBounds => write!(f, "Index out of bounds"),
+            ClacError::InvalidIndex => write!(f, "Invalid index"),
+            ClacError::DivisionByZero => write!(f, "Division by zero"),
+            ClacError::Overflow => write!(f, "Overflow"),
+            ClacError::NegativeExponent => write!(f, "Negative exponent"),
+            ClacError::NegativeSkip => write!(f, "Negative skip"),
+            ClacError::RecursionTooDeep => write!(f, "Recursion too deep"),
+            ClacError::UnknownDefinition(name) => write!(f, "Unknown definition: {}", name),
+            ClacError::EmptyDefinition => write!(f, "Empty definition"),
+            ClacError::InvalidDefinition => write!(f, "Invalid definition"),
+            ClacError::UnexpectedDefinitionEnd => write!(f, "Unexpected definition end"),
+            ClacError::Output(e) => write!(f, "Can not write output: {}", e),
+            ClacError::Native(e) => write!(f, "{}", e),
+            ClacError::Unsupported(what) => write!(f, "Can not handle {}", what),
+            ClacError::FuelExhausted(position) => write!(f, "Out of fuel {}", position),
+            ClacError::Interrupted(position) => write!(f, "Interrupted {}", position),
+        }
+    }
+}
+
+impl std::error::Error for ClacError {}
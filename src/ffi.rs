@@ -0,0 +1,185 @@
+//! C API, for embedding clacjit in C and C++ programs.
+//!
+//! The crate is also built as a `cdylib`, and the header checked in as
+//! `include/clacjit.h` is generated from this file by `build.rs`, so
+//! signatures and constants here are kept to the few forms it knows how to
+//! translate.
+//!
+//! Panics must not unwind into C, so every function catches them. Those
+//! returning a result report a panic as `CLAC_ERROR`, and the others return
+//! NULL or 0, or do nothing.
+
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::{eval, ExecutionBackend, Outcome, State};
+
+/// Backends for `clac_state_new`
+pub const CLAC_INTERPRETER: c_int = 0;
+pub const CLAC_BYTECODE: c_int = 1;
+pub const CLAC_JIT: c_int = 2;
+
+/// Results of `clac_load` and `clac_run`
+pub const CLAC_DONE: c_int = 0;
+pub const CLAC_QUIT: c_int = 1;
+pub const CLAC_ERROR: c_int = -1;
+
+/// A state, with the error of the last call that failed
+pub struct ClacState {
+    state: State,
+    error: Option<CString>,
+}
+
+impl ClacState {
+    fn fail(&mut self, message: impl ToString) -> c_int {
+        // Messages never hold a NUL, but word names could
+        let message = message.to_string().replace('\0', " ");
+        self.error = Some(CString::new(message).unwrap());
+        CLAC_ERROR
+    }
+}
+
+/// Run `body`, or return `fallback` if it panics
+fn guard<T>(fallback: T, body: impl FnOnce() -> T) -> T {
+    catch_unwind(AssertUnwindSafe(body)).unwrap_or(fallback)
+}
+
+/// Run `body` on a state, and report a panic as its error
+///
+/// # Safety
+/// `state` must be a live state.
+unsafe fn guard_state(state: *mut ClacState, body: impl FnOnce(&mut ClacState) -> c_int) -> c_int {
+    match catch_unwind(AssertUnwindSafe(|| body(&mut *state))) {
+        Ok(result) => result,
+        Err(panic) => {
+            let message = match panic.downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => match panic.downcast_ref::<String>() {
+                    Some(message) => message.clone(),
+                    None => "unknown".to_string(),
+                },
+            };
+            (*state).fail(format!("Internal error: {}", message))
+        }
+    }
+}
+
+/// Create a state with one of the backends. Returns NULL for an unknown
+/// backend, or for the JIT in builds without it. The JIT checks everything
+/// the interpreter does, so that broken programs fail the same way.
+#[no_mangle]
+pub extern "C" fn clac_state_new(backend: c_int) -> *mut ClacState {
+    guard(std::ptr::null_mut(), || new_state(backend))
+}
+
+fn new_state(backend: c_int) -> *mut ClacState {
+    let backend: Box<dyn ExecutionBackend> = match backend {
+        CLAC_INTERPRETER => Box::<crate::InterpreterBackend>::default(),
+        CLAC_BYTECODE => Box::new(crate::bytecode::BytecodeBackend::new()),
+        #[cfg(feature = "jit")]
+        CLAC_JIT => {
+            let mut backend = crate::jit::JitBackend::new();
+            backend.set_checks(crate::jit::Checks::Full);
+            Box::new(backend)
+        }
+        _ => return std::ptr::null_mut(),
+    };
+    Box::into_raw(Box::new(ClacState {
+        state: State::with_backend(backend),
+        error: None,
+    }))
+}
+
+/// Destroy a state. NULL is ignored.
+///
+/// # Safety
+/// `state` must come from `clac_state_new`, and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn clac_state_free(state: *mut ClacState) {
+    if !state.is_null() {
+        guard((), || drop(Box::from_raw(state)));
+    }
+}
+
+/// Queue source to be run by `clac_run`. Fails if it is not UTF-8.
+///
+/// # Safety
+/// `state` must be a live state, and `source` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn clac_load(state: *mut ClacState, source: *const c_char) -> c_int {
+    guard_state(state, |state| match CStr::from_ptr(source).to_str() {
+        Ok(source) => {
+            state.error = None;
+            state.state.parse(source);
+            CLAC_DONE
+        }
+        Err(e) => state.fail(format!("Source is not UTF-8: {}", e)),
+    })
+}
+
+/// Run everything queued. Returns `CLAC_QUIT` if the program ran `quit`,
+/// and `CLAC_ERROR` if it failed, with the rest of the queue dropped.
+/// After an internal error, the state can only be freed.
+///
+/// # Safety
+/// `state` must be a live state.
+#[no_mangle]
+pub unsafe extern "C" fn clac_run(state: *mut ClacState) -> c_int {
+    guard_state(state, |state| {
+        state.error = None;
+        match eval(&mut state.state) {
+            Ok(Outcome::Done) => CLAC_DONE,
+            Ok(Outcome::Quit) => CLAC_QUIT,
+            Err(e) => state.fail(e),
+        }
+    })
+}
+
+/// Number of values on the stack
+///
+/// # Safety
+/// `state` must be a live state.
+#[no_mangle]
+pub unsafe extern "C" fn clac_stack_len(state: *const ClacState) -> usize {
+    guard(0, || (*state).state.stack().len())
+}
+
+/// Values on the stack, bottom first, valid until the state changes
+///
+/// # Safety
+/// `state` must be a live state.
+#[no_mangle]
+pub unsafe extern "C" fn clac_stack(state: *const ClacState) -> *const i32 {
+    guard(std::ptr::null(), || (*state).state.stack().as_ptr())
+}
+
+/// Push a value, as input for the program
+///
+/// # Safety
+/// `state` must be a live state.
+#[no_mangle]
+pub unsafe extern "C" fn clac_push(state: *mut ClacState, value: i32) {
+    guard((), || (*state).state.push_values(&[value]));
+}
+
+/// Drop every value on the stack
+///
+/// # Safety
+/// `state` must be a live state.
+#[no_mangle]
+pub unsafe extern "C" fn clac_clear_stack(state: *mut ClacState) {
+    guard((), || (*state).state.clear_stack());
+}
+
+/// Message of the error from the last `clac_load` or `clac_run`, or NULL
+/// if it succeeded. Valid until the next call on the state.
+///
+/// # Safety
+/// `state` must be a live state.
+#[no_mangle]
+pub unsafe extern "C" fn clac_last_error(state: *const ClacState) -> *const c_char {
+    guard(std::ptr::null(), || match &(*state).error {
+        Some(message) => message.as_ptr(),
+        None => std::ptr::null(),
+    })
+}
@@ -1,73 +1,387 @@
-use std::collections::HashMap;
+pub mod cache;
+mod select;
 
-use crate::{Queue, State, Token};
-use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::backend::ExecutionBackend;
+use crate::native::Native;
+use crate::profile::{Profile, Specialized, WordProfile};
+use crate::{peephole, ClacError, Position, Queue, State, Token};
+use dynasmrt::{dynasm, AssemblyOffset, DynamicLabel, DynasmApi, DynasmLabelApi};
+
+/// Load an address that is only known when linking into a register
+macro_rules! load {
+    ($ops: expr, $relocs: expr, $reg: tt, $reloc: expr) => {
+        dynasm!($ops
+            ; mov $reg, QWORD 0
+        );
+        $relocs.push(($ops.offset().0 - 8, $reloc));
+    };
+}
+
+macro_rules! call_helper {
+    ($ops: expr, $relocs: expr, $helper: expr) => {
+        load!($ops, $relocs, rax, Reloc::Helper($helper));
+        dynasm!($ops
+            ; call rax
+        );
+    };
+}
 
 macro_rules! push_edx {
-    ($ops: expr) => {
+    ($ops: expr, $relocs: expr) => {
         dynasm!($ops
             ; mov rcx, rdi
-            ; mov rax, QWORD State::push as _
-            ; call rax
         );
+        call_helper!($ops, $relocs, Helper::Push);
     };
 }
 
 macro_rules! pop_to_eax {
-    ($ops: expr) => {
+    ($ops: expr, $relocs: expr, $pop: expr) => {
         dynasm!($ops
             ; mov rcx, rdi
-            ; mov rax, QWORD State::must_pop as _
-            ; call rax
         );
+        call_helper!($ops, $relocs, $pop);
     };
 }
 
-///// This function shall take care of non-volatile regs
-#[no_mangle]
-#[inline(never)]
-pub extern "win64" fn take_care_of_regs(code: Code, state: &mut State) {
-    use std::arch::asm;
+mod loops;
 
-    unsafe {
-        // Push non-volatile registers
-        // RBX, RBP, RDI, RSI, RSP, R12, R13, R14, R15
-        asm!(
-            "push rbx", "push rbp", "push rdi", "push rsi", "push r12", "push r13", "push r14",
-            "push r15"
-        );
-        code(state);
-        asm!(
-            "pop r15", "pop r14", "pop r13", "pop r12", "pop rsi", "pop rdi", "pop rbp", "pop rbx"
-        );
+/// Run jitted code. Errors raised by helpers, and `quit`, unwind straight
+/// back here, through any jitted and helper frames in between.
+pub fn run(code: Code, state: &mut State) -> Result<(), ClacError> {
+    let outer = state.escape;
+    let state_ptr: *mut State = state;
+    let escaped = unsafe { enter(code, state_ptr, std::ptr::addr_of_mut!((*state_ptr).escape)) };
+    state.escape = outer;
+    match state.raised.take() {
+        Some(e) if escaped != 0 => Err(e),
+        // Quitting escapes without an error
+        _ => Ok(()),
     }
 }
 
+/// Call `code` with the state, saving the non-volatile registers and the
+/// stack pointer `escape` returns to. Returns 0, or 1 after an escape.
+#[unsafe(naked)]
+unsafe extern "sysv64" fn enter(code: Code, state: *mut State, escape: *mut usize) -> u32 {
+    std::arch::naked_asm!(
+        "push rbp",
+        "mov rbp, rsp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "sub rsp, 8",
+        "mov [rdx], rsp",
+        // Jitted code uses the win64 convention, with shadow space
+        "mov rax, rdi",
+        "mov rcx, rsi",
+        "sub rsp, 32",
+        "call rax",
+        "add rsp, 32",
+        "xor eax, eax",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
+
+/// Return 1 from the `enter` that saved the stack pointer `sp`
+#[unsafe(naked)]
+unsafe extern "sysv64" fn escape(sp: usize) -> ! {
+    std::arch::naked_asm!(
+        "mov rsp, rdi",
+        "mov eax, 1",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
+
+/// Stop the running jitted code with an error. Nothing on the frames in
+/// between is dropped, so callers must not hold anything that needs it.
+fn raise(state: &mut State, error: ClacError) -> ! {
+    state.raised = Some(error);
+    unsafe { escape(state.escape) }
+}
+
+/// Unwrap a result in a helper, or raise its error
+macro_rules! check {
+    ($state: expr, $result: expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(e) => raise($state, e),
+        }
+    };
+}
+
+extern "win64" fn push(state: &mut State, value: i32) {
+    state.push(value);
+    check!(state, state.check_stack());
+}
+
+extern "win64" fn must_pop(state: &mut State) -> i32 {
+    check!(state, state.must_pop())
+}
+
+extern "win64" fn must_pick(state: &mut State, n: i32) -> i32 {
+    if n <= 0 {
+        raise(state, ClacError::InvalidIndex);
+    }
+    check!(state, state.must_pick(n as usize))
+}
+
+extern "win64" fn pop_unchecked(state: &mut State) -> i32 {
+    unsafe { state.stack.pop_unchecked() }
+}
+
+extern "win64" fn pick_unchecked(state: &State, n: usize) -> i32 {
+    unsafe { *state.stack.pick_unchecked(n - 1) }
+}
+
 extern "win64" fn print(state: &mut State) {
-    println!("{}", state.must_pop());
+    let n = check!(state, state.must_pop());
+    check!(state, state.print(n));
 }
 
 extern "win64" fn pow(state: &mut State) {
-    let b = state.must_pop();
-    let a = state.must_pop();
+    let b = check!(state, state.must_pop());
+    let a = check!(state, state.must_pop());
+    state.push(a.pow(b as u32));
+}
+
+extern "win64" fn pow_checked(state: &mut State) {
+    let b = check!(state, state.must_pop());
+    let a = check!(state, state.must_pop());
+    if b < 0 {
+        raise(state, ClacError::NegativeExponent);
+    }
     state.push(a.pow(b as u32));
 }
 
-extern "win64" fn quit() {
-    std::process::exit(0);
+extern "win64" fn div_checked(state: &mut State) {
+    let b = check!(state, state.must_pop());
+    let a = check!(state, state.must_pop());
+    if b == 0 {
+        raise(state, ClacError::DivisionByZero);
+    }
+    if b == -1 && a == i32::MIN {
+        raise(state, ClacError::Overflow);
+    }
+    state.push(a / b);
+}
+
+extern "win64" fn mod_checked(state: &mut State) {
+    let b = check!(state, state.must_pop());
+    let a = check!(state, state.must_pop());
+    if b == 0 {
+        raise(state, ClacError::DivisionByZero);
+    }
+    if b == -1 && a == i32::MIN {
+        raise(state, ClacError::Overflow);
+    }
+    state.push(a % b);
 }
 
-extern "win64" fn custom_def_fallback(_state: &mut State, name: *const u8, name_len: usize) {
+/// Maximum number of nested calls with `Checks::Full`
+const MAX_DEPTH: usize = 100_000;
+
+extern "win64" fn enter_call(state: &mut State) {
+    check!(state, state.check_return_stack(state.depth));
+    state.depth += 1;
+    if state.depth > MAX_DEPTH {
+        raise(state, ClacError::RecursionTooDeep);
+    }
+}
+
+extern "win64" fn leave_call(state: &mut State) {
+    state.depth -= 1;
+}
+
+extern "win64" fn dup(state: &mut State) {
+    let a = check!(state, state.must_pick(1));
+    state.push(a);
+    check!(state, state.check_stack());
+}
+
+extern "win64" fn over(state: &mut State) {
+    let a = check!(state, state.must_pick(2));
+    state.push(a);
+    check!(state, state.check_stack());
+}
+
+extern "win64" fn nip(state: &mut State) {
+    let a = check!(state, state.must_pop());
+    check!(state, state.must_pop());
+    state.push(a);
+}
+
+extern "win64" fn rot_rot(state: &mut State) {
+    // a b c rot rot => c a b
+    let c = check!(state, state.must_pop());
+    let b = check!(state, state.must_pop());
+    let a = check!(state, state.must_pop());
+    state.push(c);
+    state.push(a);
+    state.push(b);
+}
+
+extern "win64" fn pick_pick_less(state: &mut State, a: i32, b: i32) {
+    let x = check!(state, state.must_pick(a as usize));
+    state.push(x);
+    let y = check!(state, state.must_pick(b as usize));
+    check!(state, state.must_pop());
+    state.push(if x < y { 1 } else { 0 });
+    check!(state, state.check_stack());
+}
+
+/// Make room for a select: `inputs` values and the condition are replaced
+/// by `outputs` values. The old values can still be read through the pointer.
+extern "win64" fn select_window(state: &mut State, inputs: usize, outputs: usize) -> *mut i32 {
+    let Some(pointer) = state.stack.window(inputs + 1, outputs) else {
+        raise(state, ClacError::StackUnderflow);
+    };
+    check!(state, state.check_stack());
+    pointer
+}
+
+extern "win64" fn call_native(state: &mut State, native: *const Native) {
+    let native = unsafe { &*native };
+    check!(state, native.call(&mut state.stack));
+    check!(state, state.check_stack());
+}
+
+/// Call a native on values in registers: `args` holds the inputs, and gets
+/// the outputs
+extern "win64" fn call_native_in_place(state: &mut State, native: *const Native, args: *mut i32) {
+    let native = unsafe { &*native };
+    let effect = native.effect();
+    let inputs = unsafe { std::slice::from_raw_parts(args, effect.inputs) };
+    let outputs = check!(state, native.apply(inputs));
+    unsafe { std::ptr::copy_nonoverlapping(outputs.as_ptr(), args, effect.outputs) };
+}
+
+/// Calls after which a profiled definition is recompiled
+pub const HOT_CALLS: u64 = 1000;
+
+/// Count a call. Hot definitions are queued, for the backend to recompile
+/// once the running code is done.
+extern "win64" fn profile_enter(
+    state: &mut State,
+    word: *const Mutex<WordProfile>,
+    name: *const u8,
+    name_len: usize,
+) {
+    let calls = {
+        let mut profile = unsafe { (*word).lock().unwrap() };
+        profile.calls += 1;
+        profile.calls
+    };
+    if calls == HOT_CALLS {
+        let name = unsafe { std::slice::from_raw_parts(name, name_len) };
+        let name = std::str::from_utf8(name).unwrap().to_string();
+        // The code holds a reference of its own, which stays leaked
+        let word = unsafe {
+            Arc::increment_strong_count(word);
+            Arc::from_raw(word)
+        };
+        state.hot.push((name, word));
+    }
+}
+
+extern "win64" fn profile_if(word: &Mutex<WordProfile>, pos: usize, cond: i32) {
+    word.lock().unwrap().record_if(pos, cond);
+}
+
+extern "win64" fn profile_skip(word: &Mutex<WordProfile>, pos: usize, n: i32) {
+    word.lock().unwrap().record_skip(pos, n);
+}
+
+/// Stop the running jitted code, for `eval` to return `Outcome::Quit`
+extern "win64" fn quit(state: &mut State) {
+    state.quit = true;
+    unsafe { escape(state.escape) }
+}
+
+/// Fuel ran out, or the interrupt flag was set, at a call or loop
+/// iteration. `name` is null in top-level chunks.
+extern "win64" fn stop(state: &mut State, name: *const u8, name_len: usize, pos: usize) {
+    let position = if name.is_null() {
+        Position::TopLevel
+    } else {
+        let name = unsafe { std::slice::from_raw_parts(name, name_len) };
+        Position::Word(std::str::from_utf8(name).unwrap().to_string(), pos)
+    };
+    // Taking a unit with none left wraps around
+    if state.fuel == u64::MAX {
+        state.fuel = 0;
+        raise(state, ClacError::FuelExhausted(position));
+    }
+    state
+        .interrupt
+        .store(false, std::sync::atomic::Ordering::Relaxed);
+    raise(state, ClacError::Interrupted(position));
+}
+
+/// A skip (or a false `if`) wants to jump `n` tokens, but only `remaining`
+/// are left in the compiled queue.
+extern "win64" fn skip_past_end(state: &mut State, n: i32, remaining: usize) {
+    if n < 0 {
+        raise(state, ClacError::NegativeSkip);
+    }
+    // Let the interpreter skip the rest of the top-level queue
+    state.pending_skip = n as usize - remaining;
+}
+
+/// Same as `skip_past_end`, but inside a definition, where nothing is left
+/// to skip.
+extern "win64" fn skip_past_def_end(state: &mut State, n: i32, _remaining: usize) {
+    if n < 0 {
+        raise(state, ClacError::NegativeSkip);
+    }
+    raise(state, ClacError::QueueUnderflow);
+}
+
+/// What the cell of a word that is used but not defined yet points to
+extern "win64" fn custom_def_fallback(state: &mut State, name: *const u8, name_len: usize) {
     let name = unsafe { std::slice::from_raw_parts(name, name_len) };
-    let name = std::str::from_utf8(name).unwrap();
-    eprintln!("Custom def: {} not compiled.", name);
-    eprintln!("In future, this will fallback to the interpreter.");
-    std::process::exit(1);
+    let name = std::str::from_utf8(name).unwrap().to_string();
+    raise(state, ClacError::UnknownDefinition(name));
 }
 
-type Code = extern "win64" fn(&mut State);
+pub type Code = extern "win64" fn(&mut State);
+
+pub struct DefsMap {
+    cells: HashMap<String, *mut *const u8>,
+    /// Tokens of each definition
+    bodies: HashMap<String, Vec<Token>>,
+    /// Definitions that inlined a word, and must be recompiled when it changes
+    inlined_into: HashMap<String, HashSet<String>>,
+    /// Words implemented by the host
+    natives: HashMap<String, Arc<Native>>,
+}
 
-pub struct DefsMap(HashMap<String, *mut *const u8>);
+// Cells are leaked, like the code reading them, and only written through
+// `&mut self`, so the map can move to another thread along with its backend,
+// and be read from many threads once it is no longer changed
+unsafe impl Send for DefsMap {}
+unsafe impl Sync for DefsMap {}
 
 impl Default for DefsMap {
     fn default() -> Self {
@@ -77,20 +391,25 @@ impl Default for DefsMap {
 
 impl DefsMap {
     pub fn new() -> Self {
-        Self(HashMap::new())
+        Self {
+            cells: HashMap::new(),
+            bodies: HashMap::new(),
+            inlined_into: HashMap::new(),
+            natives: HashMap::new(),
+        }
     }
 
     pub fn reserve(&mut self, name: String) {
         let pointer = Box::leak(Box::new(custom_def_fallback as *const u8));
-        self.0.insert(name, pointer);
+        self.cells.insert(name, pointer);
     }
 
     pub fn fill(&mut self, name: &str, code: Code) {
-        if !self.0.contains_key(name) {
+        if !self.cells.contains_key(name) {
             self.reserve(name.to_string());
         }
 
-        let pointer = self.0.get(name).unwrap();
+        let pointer = self.cells.get(name).unwrap();
         let pointer_to_code = code as *const u8;
         unsafe {
             **pointer = pointer_to_code;
@@ -98,19 +417,38 @@ impl DefsMap {
     }
 
     pub fn get_first(&self, name: &str) -> *mut *const u8 {
-        *self.0.get(name).unwrap()
+        *self.cells.get(name).unwrap()
     }
 
     pub fn get_first_or_reserve(&mut self, name: String) -> *mut *const u8 {
-        if !self.0.contains_key(&name) {
+        if !self.cells.contains_key(&name) {
             self.reserve(name.clone());
         }
         self.get_first(&name)
     }
 
+    /// Remember what a compiled definition inlined
+    fn note_inlined(&mut self, def_name: &str, unit: &Unit) {
+        for (name, _) in &unit.inlined {
+            self.inlined_into
+                .entry(name.clone())
+                .or_default()
+                .insert(def_name.to_string());
+        }
+    }
+
+    pub fn native(&self, name: &str) -> Option<&Native> {
+        self.natives.get(name).map(|native| &**native)
+    }
+
+    /// Tokens of a definition
+    pub fn body(&self, name: &str) -> Option<&[Token]> {
+        self.bodies.get(name).map(|body| body.as_slice())
+    }
+
     pub fn get_second(&self, name: &str) -> Option<Code> {
-        // unsafe { *(*self.0.get(name).unwrap() as *const Code) }
-        if let Some(pointer) = self.0.get(name) {
+        // unsafe { *(*self.cells.get(name).unwrap() as *const Code) }
+        if let Some(pointer) = self.cells.get(name) {
             let pointer = *pointer as *const Code;
             Some(unsafe { *pointer })
         } else {
@@ -119,16 +457,760 @@ impl DefsMap {
     }
 }
 
+/// Runs definitions and top-level code as native code
+pub struct JitBackend {
+    defs: DefsMap,
+    cache: Option<cache::Cache>,
+    checks: Checks,
+}
+
+impl Default for JitBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JitBackend {
+    pub fn new() -> Self {
+        Self {
+            defs: DefsMap::new(),
+            cache: None,
+            checks: Checks::default(),
+        }
+    }
+
+    /// Cache compiled definitions on disk
+    pub fn set_cache(&mut self, cache: cache::Cache) {
+        self.cache = Some(cache);
+    }
+
+    /// Choose which guards jitted code has
+    pub fn set_checks(&mut self, checks: Checks) {
+        self.checks = checks;
+    }
+
+    /// Compile and bind a definition. Definitions that inlined an older
+    /// version of it are compiled again.
+    ///
+    /// With a profile, the code gathers one for the definition, and is
+    /// recompiled with it once the definition gets hot.
+    fn define_word(&mut self, name: &str, def: Queue<Token>, mut profile: Option<&mut Profile>) {
+        let body: Vec<Token> = def.iter().cloned().collect();
+        let code = self.compile_def(name, def, profile.as_deref_mut());
+        self.defs.fill(name, code);
+
+        let replaced = self.defs.natives.remove(name).is_some();
+        if self.defs.bodies.insert(name.to_string(), body).is_some() || replaced {
+            self.recompile_dependents(name, profile);
+        }
+    }
+
+    /// Bind a native to a stub calling it, so that it is called through its
+    /// cell like any other word
+    fn define_native_word(
+        &mut self,
+        name: &str,
+        native: Arc<Native>,
+        profile: Option<&mut Profile>,
+    ) {
+        let replaced = self.defs.natives.insert(name.to_string(), native).is_some();
+        let code = native_stub(name).link(&mut self.defs);
+        self.defs.fill(name, code);
+
+        if self.defs.bodies.remove(name).is_some() || replaced {
+            self.recompile_dependents(name, profile);
+        }
+    }
+
+    /// Compile the definitions that inlined an older version of a word again
+    fn recompile_dependents(&mut self, name: &str, mut profile: Option<&mut Profile>) {
+        for dependent in self.defs.inlined_into.remove(name).unwrap_or_default() {
+            let Some(body) = self.defs.bodies.get(&dependent) else {
+                continue;
+            };
+            let mut def = Queue::new();
+            for token in body {
+                def.push(token.clone());
+            }
+            let code = self.compile_def(&dependent, def, profile.as_deref_mut());
+            self.defs.fill(&dependent, code);
+        }
+    }
+
+    fn compile_def(
+        &mut self,
+        name: &str,
+        def: Queue<Token>,
+        profile: Option<&mut Profile>,
+    ) -> Code {
+        match profile {
+            // Instrumented code is not worth caching
+            Some(profile) => {
+                // Leak a reference, as the code may outlive the profile
+                let profiling = Profiling::Gather(Arc::into_raw(profile.reset(name)));
+                assemble_with(&def, Some(name), self.checks, &self.defs, profiling)
+                    .link(&mut self.defs)
+            }
+            None => cache::compile(def, name, &mut self.defs, self.checks, self.cache.as_ref()),
+        }
+    }
+
+    /// Compile a definition again, for the profile gathered so far
+    fn recompile(&mut self, name: &str, word: &mut WordProfile) {
+        let Some(body) = self.defs.body(name) else {
+            return;
+        };
+        let mut def = Queue::new();
+        for token in body {
+            def.push(token.clone());
+        }
+
+        log::info!("Recompiling {} with its profile...", name);
+        let unit = assemble_with(
+            &def,
+            Some(name),
+            self.checks,
+            &self.defs,
+            Profiling::Use(word),
+        );
+        self.defs.note_inlined(name, &unit);
+        let code = unit.link(&mut self.defs);
+        self.defs.fill(name, code);
+        word.specialized = Some(unit.specialized);
+    }
+
+    /// Recompile the definitions found hot, unless they were redefined since
+    fn recompile_hot(&mut self, state: &mut State) {
+        for (name, word) in std::mem::take(&mut state.hot) {
+            let current = state.profile.as_ref();
+            if current.is_some_and(|profile| profile.is_current(&name, &word)) {
+                self.recompile(&name, &mut word.lock().unwrap());
+            }
+        }
+    }
+}
+
+impl ExecutionBackend for JitBackend {
+    fn define(&mut self, state: &mut State, name: &str, def: Queue<Token>) {
+        log::info!("Compiling {}...", name);
+        self.define_word(name, def, state.profile.as_mut());
+    }
+
+    fn define_native(&mut self, state: &mut State, name: &str, native: Arc<Native>) {
+        self.define_native_word(name, native, state.profile.as_mut());
+    }
+
+    fn call(&self, state: &mut State, name: &str) -> Result<bool, ClacError> {
+        let Some(code) = self.defs.get_second(name) else {
+            return Ok(false);
+        };
+        run(code, state)?;
+        Ok(true)
+    }
+
+    fn after_call(&mut self, state: &mut State) {
+        self.recompile_hot(state);
+    }
+
+    fn run_chunk(&mut self, state: &mut State) -> Result<bool, ClacError> {
+        // Compile top-level code between definitions and run it natively
+        let chunk = peephole::optimize(state.take_chunk());
+        // Nothing keeps the code of a chunk once it ran
+        let linked = assemble(&chunk, None, self.checks, &self.defs).link_owned(&mut self.defs);
+        let result = run(linked.code(), state);
+        drop(linked);
+        self.recompile_hot(state);
+        result?;
+        Ok(true)
+    }
+
+    fn definitions(&self, _state: &State) -> Vec<String> {
+        self.defs
+            .bodies
+            .keys()
+            .chain(self.defs.natives.keys())
+            .cloned()
+            .collect()
+    }
+
+    fn body(&self, _state: &State, name: &str) -> Option<Vec<Token>> {
+        self.defs.body(name).map(|body| body.to_vec())
+    }
+}
+
+/// Source text of a body, as recorded for inlining
+fn body_text(body: &[Token]) -> String {
+    body.iter()
+        .map(|token| token.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// All jumps as (from, to), or `None` if a skip is not constant
+fn jumps(tokens: &[Token]) -> Option<Vec<(usize, usize)>> {
+    use Token::*;
+
+    let mut jumps = vec![];
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            If => jumps.push((i, i + 4)),
+            IfSkip(n) => jumps.extend([(i, i + 4), (i, i + 3 + *n as usize)]),
+            Skip => match i.checked_sub(1).map(|j| &tokens[j]) {
+                Some(Num(n)) if *n >= 0 => jumps.push((i, i + 1 + *n as usize)),
+                _ => return None,
+            },
+            _ => {}
+        }
+    }
+    Some(jumps)
+}
+
+/// Whether the call at `i` is the last thing the definition does: the
+/// tokens after it are only constant skips, landing right on the end
+fn is_tail(tokens: &[Token], i: usize) -> bool {
+    let mut j = i + 1;
+    while j < tokens.len() {
+        match (&tokens[j], tokens.get(j + 1)) {
+            (Token::Num(n), Some(Token::Skip)) if *n >= 0 => j += 2 + *n as usize,
+            _ => return false,
+        }
+    }
+    j == tokens.len()
+}
+
+/// Guards emitted into jitted code
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Checks {
+    /// No guards. Broken programs have undefined behaviour
+    None,
+    /// Stack underflow, and `pick` and `skip` ranges
+    #[default]
+    Underflow,
+    /// Everything the interpreter checks, plus recursion depth
+    Full,
+}
+
+impl std::str::FromStr for Checks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Checks::None),
+            "underflow" => Ok(Checks::Underflow),
+            "full" => Ok(Checks::Full),
+            _ => Err(format!("unknown checks level: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Checks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Checks::None => write!(f, "none"),
+            Checks::Underflow => write!(f, "underflow"),
+            Checks::Full => write!(f, "full"),
+        }
+    }
+}
+
+/// Rust functions called by jitted code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Helper {
+    Push,
+    MustPop,
+    MustPick,
+    Pow,
+    Print,
+    Quit,
+    SkipPastEnd,
+    SkipPastDefEnd,
+    PopUnchecked,
+    PickUnchecked,
+    PowChecked,
+    DivChecked,
+    ModChecked,
+    Enter,
+    Leave,
+    Dup,
+    Over,
+    Nip,
+    RotRot,
+    PickPickLess,
+    SelectWindow,
+    ProfileEnter,
+    ProfileIf,
+    ProfileSkip,
+    CallNative,
+    CallNativeInPlace,
+    Stop,
+}
+
+impl Helper {
+    const ALL: [Helper; 27] = [
+        Helper::Push,
+        Helper::MustPop,
+        Helper::MustPick,
+        Helper::Pow,
+        Helper::Print,
+        Helper::Quit,
+        Helper::SkipPastEnd,
+        Helper::SkipPastDefEnd,
+        Helper::PopUnchecked,
+        Helper::PickUnchecked,
+        Helper::PowChecked,
+        Helper::DivChecked,
+        Helper::ModChecked,
+        Helper::Enter,
+        Helper::Leave,
+        Helper::Dup,
+        Helper::Over,
+        Helper::Nip,
+        Helper::RotRot,
+        Helper::PickPickLess,
+        Helper::SelectWindow,
+        Helper::ProfileEnter,
+        Helper::ProfileIf,
+        Helper::ProfileSkip,
+        Helper::CallNative,
+        Helper::CallNativeInPlace,
+        Helper::Stop,
+    ];
+
+    fn address(self) -> *const () {
+        match self {
+            Helper::Push => push as *const (),
+            Helper::MustPop => must_pop as *const (),
+            Helper::MustPick => must_pick as *const (),
+            Helper::Pow => pow as *const (),
+            Helper::Print => print as *const (),
+            Helper::Quit => quit as *const (),
+            Helper::SkipPastEnd => skip_past_end as *const (),
+            Helper::SkipPastDefEnd => skip_past_def_end as *const (),
+            Helper::PopUnchecked => pop_unchecked as *const (),
+            Helper::PickUnchecked => pick_unchecked as *const (),
+            Helper::PowChecked => pow_checked as *const (),
+            Helper::DivChecked => div_checked as *const (),
+            Helper::ModChecked => mod_checked as *const (),
+            Helper::Enter => enter_call as *const (),
+            Helper::Leave => leave_call as *const (),
+            Helper::Dup => dup as *const (),
+            Helper::Over => over as *const (),
+            Helper::Nip => nip as *const (),
+            Helper::RotRot => rot_rot as *const (),
+            Helper::PickPickLess => pick_pick_less as *const (),
+            Helper::SelectWindow => select_window as *const (),
+            Helper::ProfileEnter => profile_enter as *const (),
+            Helper::ProfileIf => profile_if as *const (),
+            Helper::ProfileSkip => profile_skip as *const (),
+            Helper::CallNative => call_native as *const (),
+            Helper::CallNativeInPlace => call_native_in_place as *const (),
+            Helper::Stop => stop as *const (),
+        }
+    }
+
+    pub fn from_index(index: u8) -> Option<Helper> {
+        Self::ALL.get(index as usize).copied()
+    }
+}
+
+/// An address in the code that is only known when linking
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reloc {
+    Helper(Helper),
+    /// The `DefsMap` cell of a definition
+    Cell(String),
+    /// The name of a definition, for error messages
+    Name(String),
+    /// The address table used by `skip` and `if`
+    AddrTable,
+    /// A word implemented by the host
+    Native(String),
+    /// Offset of the fuel counter in `State`
+    Fuel,
+    /// Offset of the pointer to the interrupt flag in `State`
+    Interrupt,
+}
+
+/// Position-independent machine code, before linking
+pub struct Unit {
+    code: Vec<u8>,
+    /// Offset of the code before each token, and of the end
+    offsets: Vec<usize>,
+    /// Offsets of 64-bit immediates to patch when linking
+    relocs: Vec<(usize, Reloc)>,
+    /// Words inlined into the code, with the body text they had
+    inlined: Vec<(String, String)>,
+    /// What the code was specialized on, from a profile
+    specialized: Specialized,
+}
+
+/// Units linked and not dropped yet, leaked ones included
+static LINKED: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of linked units still alive. Definitions stay linked for good,
+/// while top-level chunks are dropped once they ran.
+pub fn linked_units() -> usize {
+    LINKED.load(Ordering::Relaxed)
+}
+
+/// Code in executable memory, with everything it points into. It must
+/// outlive any run of the code.
+pub struct Linked {
+    code: Code,
+    _buf: dynasmrt::ExecutableBuffer,
+    _addr_table: Box<[*const u8]>,
+    _names: Vec<Box<[u8]>>,
+    _natives: Vec<Arc<Native>>,
+}
+
+impl Linked {
+    pub fn code(&self) -> Code {
+        self.code
+    }
+}
+
+impl Drop for Linked {
+    fn drop(&mut self) {
+        LINKED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Unit {
+    /// Copy the code into executable memory and patch in the addresses. The
+    /// code stays there for good, as cells may point to it.
+    pub fn link(&self, defs: &mut DefsMap) -> Code {
+        let linked = self.link_owned(defs);
+        let code = linked.code;
+        std::mem::forget(linked);
+        code
+    }
+
+    /// Same as `link`, but the code is freed along with what is returned
+    pub fn link_owned(&self, defs: &mut DefsMap) -> Linked {
+        let mut addr_table = vec![std::ptr::null::<u8>(); self.offsets.len()].into_boxed_slice();
+        let mut names = vec![];
+        let mut natives = vec![];
+
+        let mut code = self.code.clone();
+        for (offset, reloc) in &self.relocs {
+            let value = match reloc {
+                Reloc::Helper(helper) => helper.address() as u64,
+                Reloc::Cell(name) => defs.get_first_or_reserve(name.clone()) as u64,
+                Reloc::Name(name) => {
+                    let name = name.clone().into_bytes().into_boxed_slice();
+                    let address = name.as_ptr() as u64;
+                    names.push(name);
+                    address
+                }
+                Reloc::AddrTable => addr_table.as_ptr() as u64,
+                Reloc::Native(name) => {
+                    let native = defs.natives[name].clone();
+                    let address = Arc::as_ptr(&native) as u64;
+                    natives.push(native);
+                    address
+                }
+                Reloc::Fuel => std::mem::offset_of!(State, fuel) as u64,
+                Reloc::Interrupt => std::mem::offset_of!(State, interrupt_flag) as u64,
+            };
+            code[*offset..*offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+
+        let mut buf = dynasmrt::mmap::MutableBuffer::new(code.len()).unwrap();
+        buf.set_len(code.len());
+        buf.copy_from_slice(&code);
+        let buf = buf.make_exec().unwrap();
+
+        // Fill address table
+        for (i, off) in self.offsets.iter().enumerate() {
+            addr_table[i] = buf.ptr(AssemblyOffset(*off));
+        }
+
+        LINKED.fetch_add(1, Ordering::Relaxed);
+        Linked {
+            code: unsafe { std::mem::transmute::<*const u8, Code>(buf.ptr(AssemblyOffset(0))) },
+            _buf: buf,
+            _addr_table: addr_table,
+            _names: names,
+            _natives: natives,
+        }
+    }
+}
+
+/// Code for the cell of a native: a jump to `call_native`
+fn native_stub(name: &str) -> Unit {
+    let mut ops = dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(0);
+    let mut relocs = vec![];
+    dynasm!(ops
+        ; .arch x64
+    );
+    load!(ops, relocs, rdx, Reloc::Native(name.to_string()));
+    load!(ops, relocs, rax, Reloc::Helper(Helper::CallNative));
+    dynasm!(ops
+        ; jmp rax
+    );
+    Unit {
+        code: ops.finalize().unwrap(),
+        offsets: vec![],
+        relocs,
+        inlined: vec![],
+        specialized: Specialized::default(),
+    }
+}
+
 pub fn compile(
     queue: Queue<Token>,
     def_name: Option<&str>, // Optional. If provided, we can do tail-recursion optimization
+    // If not provided, the queue is a top-level chunk
     defs: &mut DefsMap,
+    checks: Checks,
 ) -> extern "win64" fn(&mut State) {
+    assemble(&queue, def_name, checks, defs).link(defs)
+}
+
+/// Take a unit of fuel, and go to `fueled` unless it ran out or the
+/// interrupt flag is set. Falls through to code that must raise with
+/// `emit_stop` otherwise.
+fn emit_burn(
+    ops: &mut dynasmrt::VecAssembler<dynasmrt::x64::X64Relocation>,
+    relocs: &mut Vec<(usize, Reloc)>,
+    fueled: DynamicLabel,
+) {
+    load!(ops, relocs, rax, Reloc::Fuel);
+    dynasm!(ops
+        ; sub QWORD [rdi + rax], 1
+        ; jc >stop
+    );
+    load!(ops, relocs, rax, Reloc::Interrupt);
+    dynasm!(ops
+        ; mov rax, [rdi + rax]
+        ; cmp BYTE [rax], 0
+        ; je =>fueled
+        ;stop:
+    );
+}
+
+/// Raise whatever stopped the program at the token at `pos`
+fn emit_stop(
+    ops: &mut dynasmrt::VecAssembler<dynasmrt::x64::X64Relocation>,
+    relocs: &mut Vec<(usize, Reloc)>,
+    def_name: Option<&str>,
+    pos: usize,
+) {
+    dynasm!(ops
+        ; mov rcx, rdi
+    );
+    match def_name {
+        Some(name) => {
+            load!(ops, relocs, rdx, Reloc::Name(name.to_string()));
+            dynasm!(ops
+                ; mov r8, QWORD name.len() as _
+            );
+        }
+        None => dynasm!(ops
+            ; xor edx, edx
+            ; xor r8d, r8d
+        ),
+    }
+    dynasm!(ops
+        ; mov r9, QWORD pos as _
+    );
+    call_helper!(ops, relocs, Helper::Stop);
+}
+
+/// Branchless code for a select. Reads the condition and the inputs from
+/// the stack, then overwrites them with the outputs.
+fn emit_select(
+    ops: &mut dynasmrt::VecAssembler<dynasmrt::x64::X64Relocation>,
+    relocs: &mut Vec<(usize, Reloc)>,
+    select: &select::Select,
+) {
+    use select::Val;
+
+    let inputs = select.inputs;
+    let outputs = select.then_out.len();
+
+    // rax = pointer to the inputs, with the condition right above
+    dynasm!(ops
+        ; mov rcx, rdi
+        ; mov edx, DWORD inputs as _
+        ; mov r8d, DWORD outputs as _
+    );
+    call_helper!(ops, relocs, Helper::SelectWindow);
+
+    // Inputs go to r8d, r9d, r10d
+    let input_reg = |n: usize| 8 + n as u8;
+    dynasm!(ops
+        ; mov ecx, [rax + 4 * inputs as i32]
+    );
+    for n in 0..inputs {
+        dynasm!(ops
+            ; mov Rd(input_reg(n)), [rax + 4 * (inputs - 1 - n) as i32]
+        );
+    }
+    dynasm!(ops
+        ; test ecx, ecx
+    );
+
+    for (j, (then_val, else_val)) in select.then_out.iter().zip(&select.else_out).enumerate() {
+        match else_val {
+            Val::Input(n) => dynasm!(ops
+                ; mov edx, Rd(input_reg(*n))
+            ),
+            Val::Const(x) => dynasm!(ops
+                ; mov edx, DWORD *x
+            ),
+        }
+        if then_val != else_val {
+            match then_val {
+                Val::Input(n) => dynasm!(ops
+                    ; cmovnz edx, Rd(input_reg(*n))
+                ),
+                Val::Const(x) => dynasm!(ops
+                    ; mov r11d, DWORD *x
+                    ; cmovnz edx, r11d
+                ),
+            }
+        }
+        dynasm!(ops
+            ; mov [rax + 4 * j as i32], edx
+        );
+    }
+}
+
+/// How code relates to profiles
+#[derive(Clone, Copy)]
+enum Profiling<'a> {
+    Off,
+    /// Count calls, branches and skips into the profile of the definition,
+    /// for the backend to recompile it once it is hot
+    Gather(*const Mutex<WordProfile>),
+    /// Lay out and specialize the code for the profile
+    Use(&'a WordProfile),
+}
+
+/// Record the value in eax for the token at `pos`, keeping eax
+fn emit_record(
+    ops: &mut dynasmrt::VecAssembler<dynasmrt::x64::X64Relocation>,
+    relocs: &mut Vec<(usize, Reloc)>,
+    helper: Helper,
+    word: *const Mutex<WordProfile>,
+    pos: usize,
+) {
+    dynasm!(ops
+        ; push rax
+        ; sub rsp, BYTE 8
+        ; mov rcx, QWORD word as _
+        ; mov edx, DWORD pos as _
+        ; mov r8d, eax
+    );
+    call_helper!(ops, relocs, helper);
+    dynasm!(ops
+        ; add rsp, BYTE 8
+        ; pop rax
+    );
+}
+
+/// Arms of `if`s the profile says are almost never run. They are moved
+/// after the epilogue, so that the hot path is contiguous.
+fn cold_ranges(
+    tokens: &[Token],
+    profile: Option<&WordProfile>,
+    checks: Checks,
+) -> Vec<Range<usize>> {
+    use Token::*;
+
+    let Some(profile) = profile else {
+        return vec![];
+    };
+
+    // Positions where code for a token (or a select) starts. Moved ranges
+    // must not cut through anything else.
+    let mut starts = vec![false; tokens.len() + 1];
+    let mut i = 0;
+    while i < tokens.len() {
+        starts[i] = true;
+        i += match &tokens[i] {
+            If | IfSkip(_) => {
+                find_select(tokens, i, checks).map_or(tokens[i].width(), |s| s.join - i)
+            }
+            token => token.width(),
+        };
+    }
+    starts[tokens.len()] = true;
+
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for (i, token) in tokens.iter().enumerate() {
+        let range = match (token, profile.branch_bias(i)) {
+            // The condition is almost always zero
+            (If, Some(true)) => i + 1..i + 4,
+            // The condition is almost never zero
+            (IfSkip(n), Some(false)) => i + 4..i + 3 + *n as usize,
+            _ => continue,
+        };
+        let fits = !range.is_empty()
+            && range.end <= tokens.len()
+            && starts[i]
+            && starts[range.start]
+            && starts[range.end]
+            && find_select(tokens, i, checks).is_none();
+        if fits && ranges.last().is_none_or(|last| last.end <= range.start) {
+            ranges.push(range);
+        }
+    }
+    ranges
+}
+
+/// The select starting at `i`, if any. `Checks::Full` has none, as a select
+/// that fails leaves the stack as it was, not as the interpreter leaves it.
+fn find_select(tokens: &[Token], i: usize, checks: Checks) -> Option<select::Select> {
+    match checks {
+        Checks::Full => None,
+        _ => select::find(tokens, i),
+    }
+}
+
+/// Generate position-independent code for a queue
+pub fn assemble(
+    queue: &Queue<Token>,
+    def_name: Option<&str>,
+    checks: Checks,
+    defs: &DefsMap,
+) -> Unit {
+    assemble_with(queue, def_name, checks, defs, Profiling::Off)
+}
+
+fn assemble_with(
+    queue: &Queue<Token>,
+    def_name: Option<&str>,
+    checks: Checks,
+    defs: &DefsMap,
+    profiling: Profiling,
+) -> Unit {
     use Token::*;
 
-    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+    let tokens: Vec<Token> = queue.iter().cloned().collect();
+    let len = tokens.len();
+
+    let (gather, profile) = match profiling {
+        Profiling::Off => (None, None),
+        Profiling::Gather(word) => (Some(word), None),
+        Profiling::Use(word) => (None, Some(word)),
+    };
+
+    // Loops keep their values in registers, but can not gather profiles
+    if let (Some(def_name), None) = (def_name, gather) {
+        if let Some(unit) = loops::assemble(&tokens, def_name, checks, defs) {
+            return unit;
+        }
+    }
+
+    let pop = match checks {
+        Checks::None => Helper::PopUnchecked,
+        _ => Helper::MustPop,
+    };
 
-    let entry = ops.offset();
+    let mut ops = dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(0);
+    let mut relocs = vec![];
 
     // Prelude
     dynasm!(ops
@@ -144,88 +1226,168 @@ pub fn compile(
         ;entry:
     );
 
+    if let (Some(word), Some(name)) = (gather, def_name) {
+        dynasm!(ops
+            ; mov rcx, rdi
+            ; mov rdx, QWORD word as _
+        );
+        load!(ops, relocs, r8, Reloc::Name(name.to_string()));
+        dynasm!(ops
+            ; mov r9, QWORD name.len() as _
+        );
+        call_helper!(ops, relocs, Helper::ProfileEnter);
+    }
+
     // Stores the offset before each token
-    let mut addr_table = Vec::<*const u8>::with_capacity(queue.len() + 1);
+    let mut offsets = vec![0; len + 1];
+    let labels: Vec<_> = (0..=len).map(|_| ops.new_dynamic_label()).collect();
 
-    // Fill it with dummy values
-    for _ in 0..queue.len() {
-        addr_table.push(std::ptr::null());
+    // Hot code first, then the epilogue, then cold ranges
+    let cold = cold_ranges(&tokens, profile, checks);
+    let mut specialized = Specialized {
+        cold: cold.clone(),
+        skips: vec![],
+    };
+    let mut order: Vec<usize> = (0..=len)
+        .filter(|i| !cold.iter().any(|range| range.contains(i)))
+        .collect();
+    for range in &cold {
+        order.extend(range.clone());
     }
-    addr_table.push(std::ptr::null());
+    // Where the code emitted last continues
+    let mut fallthrough = Some(0);
 
-    let addr_table = addr_table.leak();
+    // Skips past the end of the queue land here
+    let end_label = ops.new_dynamic_label();
+    let skip_past_end = if def_name.is_some() {
+        Helper::SkipPastDefEnd
+    } else {
+        Helper::SkipPastEnd
+    };
 
-    let mut offsets = vec![];
+    // Codegen
+    // Tokens left that the last superinstruction covers
+    let mut covered = 0;
 
-    let addr_table_ptr = addr_table.as_ptr();
+    for i in order {
+        if covered > 0 {
+            offsets[i] = ops.offset().0;
+            dynasm!(ops
+                ; =>labels[i]
+            );
+            covered -= 1;
+            continue;
+        }
+        if let Some(next) = fallthrough.filter(|next| *next != i) {
+            dynasm!(ops
+                ; jmp =>labels[next]
+            );
+        }
+        offsets[i] = ops.offset().0;
+        dynasm!(ops
+            ; =>labels[i]
+        );
+
+        if i == len {
+            dynasm!(ops
+                ; =>end_label
+                // Epilogue
+                ; leave
+                ; ret
+            );
+            fallthrough = None;
+            continue;
+        }
+
+        let token = &tokens[i];
+        covered = token.width() - 1;
+        fallthrough = Some(i + token.width());
+
+        if matches!(token, If | IfSkip(_)) {
+            if let Some(select) = find_select(&tokens, i, checks) {
+                emit_select(&mut ops, &mut relocs, &select);
+                covered = select.join - i - 1;
+                fallthrough = Some(select.join);
+                continue;
+            }
+        }
 
-    // Codegen
-    for (i, token) in queue.iter().enumerate() {
-        offsets.push(ops.offset());
         match token {
             Num(x) => {
                 dynasm!(ops
                     ; mov edx, DWORD *x
                 );
-                push_edx!(ops);
+                push_edx!(ops, relocs);
             }
             Add => {
                 // Pop 2, add them, push the result
-                pop_to_eax!(ops);
+                pop_to_eax!(ops, relocs, pop);
                 dynasm!(ops
                     // Push to stack
                     ; push rax
                     ; sub rsp, BYTE 8 // For 16-byte alignment
                 );
-                pop_to_eax!(ops);
+                pop_to_eax!(ops, relocs, pop);
                 dynasm!(ops
                     ; add rsp, BYTE 8
                     ; pop rdx
                     ; add edx, eax // edx = edx + eax
                 );
-                push_edx!(ops);
+                push_edx!(ops, relocs);
             }
             Sub => {
                 // Pop 2, subtract them, push the result
-                pop_to_eax!(ops);
+                pop_to_eax!(ops, relocs, pop);
                 dynasm!(ops
                     // Push to stack
                     ; push rax
                     ; sub rsp, BYTE 8 // For 16-byte alignment
                 );
-                pop_to_eax!(ops);
+                pop_to_eax!(ops, relocs, pop);
                 dynasm!(ops
                     ; add rsp, BYTE 8
                     ; pop rdx
                     ; sub eax, edx // eax = eax - edx
                     ; mov edx, eax
                 );
-                push_edx!(ops);
+                push_edx!(ops, relocs);
             }
             Mul => {
                 // Pop 2, multiply them, push the result
-                pop_to_eax!(ops);
+                pop_to_eax!(ops, relocs, pop);
                 dynasm!(ops
                     // Push to stack
                     ; push rax
                     ; sub rsp, BYTE 8 // For 16-byte alignment
                 );
-                pop_to_eax!(ops);
+                pop_to_eax!(ops, relocs, pop);
                 dynasm!(ops
                     ; add rsp, BYTE 8
                     ; pop rdx
                     ; imul edx, eax // edx = edx * eax
                 );
-                push_edx!(ops);
+                push_edx!(ops, relocs);
+            }
+            Div if checks == Checks::Full => {
+                dynasm!(ops
+                    ; mov rcx, rdi
+                );
+                call_helper!(ops, relocs, Helper::DivChecked);
+            }
+            Mod if checks == Checks::Full => {
+                dynasm!(ops
+                    ; mov rcx, rdi
+                );
+                call_helper!(ops, relocs, Helper::ModChecked);
             }
             Div => {
                 // a b / => a / b
-                pop_to_eax!(ops); // Pop b
+                pop_to_eax!(ops, relocs, pop); // Pop b
                 dynasm!(ops
                     ; push rax
                     ; sub rsp, BYTE 8
                 );
-                pop_to_eax!(ops); // Pop a
+                pop_to_eax!(ops, relocs, pop); // Pop a
                 dynasm!(ops
                     ; add rsp, BYTE 8
                     ; pop rbx // ebx = b
@@ -233,16 +1395,16 @@ pub fn compile(
                     ; idiv ebx // eax = eax / ebx = a / b
                     ; mov edx, eax
                 );
-                push_edx!(ops);
+                push_edx!(ops, relocs);
             }
             Mod => {
                 // a b % => a % b
-                pop_to_eax!(ops); // Pop b
+                pop_to_eax!(ops, relocs, pop); // Pop b
                 dynasm!(ops
                     ; push rax
                     ; sub rsp, BYTE 8
                 );
-                pop_to_eax!(ops); // Pop a
+                pop_to_eax!(ops, relocs, pop); // Pop a
                 dynasm!(ops
                     ; add rsp, BYTE 8
                     ; pop rbx // ebx = b
@@ -250,38 +1412,41 @@ pub fn compile(
                     ; idiv ebx // eax = eax % ebx = a % b
                     ; mov edx, edx
                 );
-                push_edx!(ops);
+                push_edx!(ops, relocs);
             }
             Pow => {
                 dynasm!(ops
                     ; mov rcx, rdi
-                    ; mov rax, QWORD pow as _
-                    ; call rax
                 );
+                if checks == Checks::Full {
+                    call_helper!(ops, relocs, Helper::PowChecked);
+                } else {
+                    call_helper!(ops, relocs, Helper::Pow);
+                }
             }
             Drop => {
-                pop_to_eax!(ops);
+                pop_to_eax!(ops, relocs, pop);
             }
             Swap => {
                 // a b swap => b a
-                pop_to_eax!(ops);
+                pop_to_eax!(ops, relocs, pop);
                 dynasm!(ops
                     ; push rax
                     ; sub rsp, BYTE 8
                 );
-                pop_to_eax!(ops);
+                pop_to_eax!(ops, relocs, pop);
                 dynasm!(ops
                     ; add rsp, BYTE 8
                     ; pop rdx
                     ; push rax
                     ; sub rsp, BYTE 8
                 );
-                push_edx!(ops);
+                push_edx!(ops, relocs);
                 dynasm!(ops
                     ; add rsp, BYTE 8
                     ; pop rdx
                 );
-                push_edx!(ops);
+                push_edx!(ops, relocs);
             }
             Rot => {
                 // We are going to use R13, R14
@@ -290,29 +1455,29 @@ pub fn compile(
                     ; push r14
                 );
                 // a b c rot => b c a
-                pop_to_eax!(ops); // c
+                pop_to_eax!(ops, relocs, pop); // c
                 dynasm!(ops
                     ; mov r13, rax // r13 = c
                 );
-                pop_to_eax!(ops); // b
+                pop_to_eax!(ops, relocs, pop); // b
                 dynasm!(ops
                     ; mov r14, rax // r14 = b
                 );
                 // The stack is now: b, c
-                pop_to_eax!(ops); // a
+                pop_to_eax!(ops, relocs, pop); // a
                 dynasm!(ops
                     ; mov rdx, r14 // rax = b
                     ; mov r14, rax // r14 = a
                 );
-                push_edx!(ops); // Push b
+                push_edx!(ops, relocs); // Push b
                 dynasm!(ops
                     ; mov rdx, r13 // rax = c
                 );
-                push_edx!(ops); // Push c
+                push_edx!(ops, relocs); // Push c
                 dynasm!(ops
                     ; mov rdx, r14 // rax = a
                 );
-                push_edx!(ops); // Push a
+                push_edx!(ops, relocs); // Push a
                 dynasm!(ops
                     ; pop r14
                     ; pop r13
@@ -320,12 +1485,12 @@ pub fn compile(
             }
             Less => {
                 // a b < => 1 if a < b else 0
-                pop_to_eax!(ops); // Pop b
+                pop_to_eax!(ops, relocs, pop); // Pop b
                 dynasm!(ops
                     ; push rax
                     ; sub rsp, BYTE 8
                 );
-                pop_to_eax!(ops); // Pop a
+                pop_to_eax!(ops, relocs, pop); // Pop a
                 dynasm!(ops
                     ; add rsp, BYTE 8
                     ; pop rbx // ebx = b
@@ -333,70 +1498,182 @@ pub fn compile(
                     ; setl al
                     ; movzx edx, al // edx = 1 if a < b else 0
                 );
-                push_edx!(ops);
+                push_edx!(ops, relocs);
             }
             Pick => {
                 // n pick
                 // Push the n-th element in the stack
-                pop_to_eax!(ops); // eax = n
+                pop_to_eax!(ops, relocs, pop); // eax = n
                 dynasm!(ops
                     ; mov edx, eax // edx = n
                     ; mov rcx, rdi
-                    ; mov rax, QWORD State::must_pick as _
-                    ; call rax
+                );
+                if checks == Checks::None {
+                    call_helper!(ops, relocs, Helper::PickUnchecked);
+                } else {
+                    call_helper!(ops, relocs, Helper::MustPick);
+                }
+                dynasm!(ops
                     ; mov edx, eax
                 );
-                push_edx!(ops);
+                push_edx!(ops, relocs);
             }
             Skip => {
                 // n skip
                 // Jump to n+i+1 th address in the table
                 let j = i + 1;
-                pop_to_eax!(ops); // eax = n
+                let remaining = queue.len() - j;
+                pop_to_eax!(ops, relocs, pop); // eax = n
+
+                // Specialize skips without a constant count on their usual count
+                let dynamic = !matches!(i.checked_sub(1).map(|k| &tokens[k]), Some(Num(_)));
+                if let (true, Some(word)) = (dynamic, gather) {
+                    emit_record(&mut ops, &mut relocs, Helper::ProfileSkip, word, i);
+                }
+                let usual = profile
+                    .filter(|_| dynamic)
+                    .and_then(|word| word.dominant_skip(i))
+                    .filter(|n| *n >= 0 && *n as usize <= remaining);
+                if let Some(n) = usual {
+                    specialized.skips.push((i, n));
+                    dynasm!(ops
+                        ; cmp eax, DWORD n
+                        ; je =>labels[j + n as usize]
+                    );
+                }
+                // Skipping past the end of top-level chunks is not an error
+                if checks != Checks::None || def_name.is_none() {
+                    dynasm!(ops
+                        // Unsigned compare also catches negative n
+                        ; cmp eax, DWORD remaining as _
+                        ; ja >out_of_range
+                    );
+                }
                 dynasm!(ops
                     ; mov edx, DWORD j as _ // edx = i+1
                     ; add eax, edx // eax = n + i + 1
-                    ; mov rdx, QWORD addr_table_ptr as _
+                );
+                load!(ops, relocs, rdx, Reloc::AddrTable);
+                dynasm!(ops
                     ; mov rdx, [rdx + rax * 8] // rdx = addr_table[n+i]
                     ; jmp rdx
+                    ;out_of_range:
+                    ; mov edx, eax // edx = n
+                    ; mov r8, QWORD remaining as _
+                    ; mov rcx, rdi
+                );
+                call_helper!(ops, relocs, skip_past_end);
+                dynasm!(ops
+                    ; jmp =>end_label
                 );
             }
             If => {
                 // cond if a b c
                 // if cond == 0: Jump to i+4 th
                 let j = i + 4;
-                pop_to_eax!(ops); // cond
-                dynasm!(ops
-                    ; test eax, eax
-                    ; jnz >non_zero
-                    ; mov edx, DWORD j as _ // edx = i+4
-                    ; mov rcx, QWORD addr_table_ptr as _
-                    ; mov rcx, [rcx + rdx * 8] // rcx = addr_table[i+4]
-                    ; jmp rcx
-                    ;non_zero:
-                );
+                pop_to_eax!(ops, relocs, pop); // cond
+                if let Some(word) = gather {
+                    emit_record(&mut ops, &mut relocs, Helper::ProfileIf, word, i);
+                }
+                if j > queue.len() {
+                    // Jumping past the end of the queue
+                    dynasm!(ops
+                        ; test eax, eax
+                        ; jnz >non_zero
+                        ; mov edx, DWORD (j - queue.len()) as _
+                        ; xor r8, r8
+                        ; mov rcx, rdi
+                    );
+                    call_helper!(ops, relocs, skip_past_end);
+                    dynasm!(ops
+                        ; jmp =>end_label
+                        ;non_zero:
+                    );
+                    continue;
+                }
+                if cold.iter().any(|range| range.start == i + 1) {
+                    // The three tokens are out of line
+                    dynasm!(ops
+                        ; test eax, eax
+                        ; jnz =>labels[i + 1]
+                    );
+                    fallthrough = Some(j);
+                } else {
+                    dynasm!(ops
+                        ; test eax, eax
+                        ; jz =>labels[j]
+                    );
+                }
             }
             Print => {
                 dynasm!(ops
                     ; mov rcx, rdi
-                    ; mov rax, QWORD print as _
-                    ; call rax
                 );
+                call_helper!(ops, relocs, Helper::Print);
             }
             Quit => {
                 dynasm!(ops
-                    ; mov rax, QWORD quit as _
-                    ; call rax
+                    ; mov rcx, rdi
                 );
+                call_helper!(ops, relocs, Helper::Quit);
             }
             DefBegin | DefEnd => {
                 panic!("Can not compile definition tokens");
             }
+            Dup | Over | Nip | RotRot => {
+                let helper = match token {
+                    Dup => Helper::Dup,
+                    Over => Helper::Over,
+                    Nip => Helper::Nip,
+                    _ => Helper::RotRot,
+                };
+                dynasm!(ops
+                    ; mov rcx, rdi
+                );
+                call_helper!(ops, relocs, helper);
+            }
+            PickPickLess(a, b) => {
+                dynasm!(ops
+                    ; mov rcx, rdi
+                    ; mov edx, DWORD *a
+                    ; mov r8d, DWORD *b
+                );
+                call_helper!(ops, relocs, Helper::PickPickLess);
+            }
+            IfSkip(n) => {
+                // cond if n skip _
+                // if cond != 0: Jump to i+3+n th
+                let j = i + 3 + *n as usize;
+                pop_to_eax!(ops, relocs, pop); // cond
+                if let Some(word) = gather {
+                    emit_record(&mut ops, &mut relocs, Helper::ProfileIf, word, i);
+                }
+                if cold.iter().any(|range| range.start == i + 4) {
+                    // The zero case is out of line
+                    dynasm!(ops
+                        ; test eax, eax
+                        ; jz =>labels[i + 4]
+                    );
+                    fallthrough = Some(j);
+                } else {
+                    dynasm!(ops
+                        ; test eax, eax
+                        ; jnz =>labels[j]
+                    );
+                }
+            }
             Custom(name) => {
+                let fueled = ops.new_dynamic_label();
+                emit_burn(&mut ops, &mut relocs, fueled);
+                emit_stop(&mut ops, &mut relocs, def_name, i);
+                dynasm!(ops
+                    ; =>fueled
+                );
+
                 // Check if is doing tail recursion
                 if let Some(def_name) = def_name {
-                    if name == def_name {
-                        println!("Tail recursion optimization enabled for {}", name);
+                    if name == def_name && is_tail(&tokens, i) {
+                        log::info!("Tail recursion optimization enabled for {}", name);
                         // Tail recursion optimization
                         dynasm!(ops
                             ; jmp <entry
@@ -405,41 +1682,48 @@ pub fn compile(
                     }
                 }
 
-                let first_pointer = defs.get_first_or_reserve(name.clone());
-                // Leak the name to make it live long enough
-                let ptr_name = name.clone().into_bytes().leak();
-                let ptr_name = ptr_name.as_ptr();
+                if checks == Checks::Full {
+                    dynasm!(ops
+                        ; mov rcx, rdi
+                    );
+                    call_helper!(ops, relocs, Helper::Enter);
+                }
 
                 // Call (*pointer) with state, name, name_len
                 dynasm!(ops
                     ; mov rcx, rdi
-                    ; mov rdx, QWORD ptr_name as _
+                );
+                load!(ops, relocs, rdx, Reloc::Name(name.clone()));
+                dynasm!(ops
                     ; mov r8, QWORD name.len() as _
-                    ; mov rax, QWORD first_pointer as _
+                );
+                load!(ops, relocs, rax, Reloc::Cell(name.clone()));
+                dynasm!(ops
                     ; mov rax, [rax]
                     ; call rax
                 );
+
+                if checks == Checks::Full {
+                    dynasm!(ops
+                        ; mov rcx, rdi
+                    );
+                    call_helper!(ops, relocs, Helper::Leave);
+                }
             }
         }
     }
-
-    let end = ops.offset();
-    dynasm!(ops
-        // Epilogue
-        ; leave
-        ; ret
-    );
-
-    let buf = ops.finalize().unwrap();
-
-    // We here need to leak buf to make it live long enough
-    let buf = Box::leak(Box::new(buf));
-
-    // Fill address table
-    for (i, off) in offsets.into_iter().enumerate() {
-        addr_table[i] = buf.ptr(off);
+    // Back from the last cold range
+    if let Some(next) = fallthrough {
+        dynasm!(ops
+            ; jmp =>labels[next]
+        );
     }
-    addr_table[queue.len()] = buf.ptr(end);
 
-    unsafe { std::mem::transmute(buf.ptr(entry)) }
+    Unit {
+        code: ops.finalize().unwrap(),
+        offsets,
+        relocs,
+        inlined: vec![],
+        specialized,
+    }
 }
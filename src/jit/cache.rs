@@ -0,0 +1,228 @@
+//! On-disk cache of compiled definitions.
+//!
+//! Each definition is stored as a `Unit` in its own file, named after a hash
+//! of the compiler version and the definition tokens. The key itself is also
+//! stored, so hash collisions are detected on load.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use super::{Checks, Helper, Reloc, Unit};
+use crate::profile::Specialized;
+use crate::{Queue, Token};
+
+const MAGIC: &[u8; 8] = b"CLACJITC";
+
+/// Bump this whenever the generated code changes
+const CACHE_VERSION: u32 = 12;
+
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn key(queue: &Queue<Token>, def_name: &str, checks: Checks) -> String {
+        let mut key = format!(
+            "{} {} {} {}\n{}",
+            env!("CARGO_PKG_VERSION"),
+            CACHE_VERSION,
+            std::env::consts::ARCH,
+            checks,
+            def_name
+        );
+        for token in queue.iter() {
+            key.push(' ');
+            key.push_str(&token.to_string());
+        }
+        key
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        // FNV-1a, stable across runs and Rust versions
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in key.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        self.dir.join(format!("{:016x}.bin", hash))
+    }
+
+    /// Find a compiled definition. Missing or malformed entries are misses.
+    pub fn load(&self, queue: &Queue<Token>, def_name: &str, checks: Checks) -> Option<Unit> {
+        let key = Self::key(queue, def_name, checks);
+        let bytes = std::fs::read(self.path(&key)).ok()?;
+
+        let mut reader = Reader(&bytes);
+        if reader.take(MAGIC.len())? != MAGIC || reader.string()? != key {
+            return None;
+        }
+
+        let code = reader.bytes()?.to_vec();
+        let offsets = (0..reader.u32()?)
+            .map(|_| reader.u32().map(|off| off as usize))
+            .collect::<Option<Vec<_>>>()?;
+        let relocs = (0..reader.u32()?)
+            .map(|_| {
+                let offset = reader.u32()? as usize;
+                let reloc = match reader.u8()? {
+                    0 => Reloc::Helper(Helper::from_index(reader.u8()?)?),
+                    1 => Reloc::Cell(reader.string()?),
+                    2 => Reloc::Name(reader.string()?),
+                    3 => Reloc::AddrTable,
+                    4 => Reloc::Native(reader.string()?),
+                    5 => Reloc::Fuel,
+                    6 => Reloc::Interrupt,
+                    _ => return None,
+                };
+                Some((offset, reloc))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let inlined = (0..reader.u32()?)
+            .map(|_| Some((reader.string()?, reader.string()?)))
+            .collect::<Option<Vec<_>>>()?;
+
+        // Do not patch or jump outside of the code
+        if relocs.iter().any(|(offset, _)| offset + 8 > code.len())
+            || offsets.iter().any(|off| *off > code.len())
+        {
+            return None;
+        }
+
+        Some(Unit {
+            code,
+            offsets,
+            relocs,
+            inlined,
+            specialized: Specialized::default(),
+        })
+    }
+
+    pub fn store(
+        &self,
+        queue: &Queue<Token>,
+        def_name: &str,
+        checks: Checks,
+        unit: &Unit,
+    ) -> std::io::Result<()> {
+        let key = Self::key(queue, def_name, checks);
+
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        write_bytes(&mut out, key.as_bytes());
+        write_bytes(&mut out, &unit.code);
+        write_u32(&mut out, unit.offsets.len());
+        for off in &unit.offsets {
+            write_u32(&mut out, *off);
+        }
+        write_u32(&mut out, unit.relocs.len());
+        for (offset, reloc) in &unit.relocs {
+            write_u32(&mut out, *offset);
+            match reloc {
+                Reloc::Helper(helper) => out.extend_from_slice(&[0, *helper as u8]),
+                Reloc::Cell(name) => {
+                    out.push(1);
+                    write_bytes(&mut out, name.as_bytes());
+                }
+                Reloc::Name(name) => {
+                    out.push(2);
+                    write_bytes(&mut out, name.as_bytes());
+                }
+                Reloc::AddrTable => out.push(3),
+                Reloc::Native(name) => {
+                    out.push(4);
+                    write_bytes(&mut out, name.as_bytes());
+                }
+                Reloc::Fuel => out.push(5),
+                Reloc::Interrupt => out.push(6),
+            }
+        }
+        write_u32(&mut out, unit.inlined.len());
+        for (name, body) in &unit.inlined {
+            write_bytes(&mut out, name.as_bytes());
+            write_bytes(&mut out, body.as_bytes());
+        }
+
+        // Write to a temporary file first, so readers never see half an entry
+        let path = self.path(&key);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        std::fs::File::create(&tmp)?.write_all(&out)?;
+        std::fs::rename(tmp, path)
+    }
+}
+
+/// Compile a definition, going through the cache if there is one
+pub fn compile(
+    queue: Queue<Token>,
+    def_name: &str,
+    defs: &mut super::DefsMap,
+    checks: Checks,
+    cache: Option<&Cache>,
+) -> super::Code {
+    // Inlined words must not have changed since the code was cached
+    let unit = cache
+        .and_then(|cache| cache.load(&queue, def_name, checks))
+        .filter(|unit| {
+            unit.inlined
+                .iter()
+                .all(|(name, body)| defs.body(name).map(super::body_text).as_ref() == Some(body))
+        });
+
+    let unit = unit.unwrap_or_else(|| {
+        let unit = super::assemble(&queue, Some(def_name), checks, defs);
+        if let Some(cache) = cache {
+            if let Err(e) = cache.store(&queue, def_name, checks, &unit) {
+                log::warn!("Failed to cache {}: {}", def_name, e);
+            }
+        }
+        unit
+    });
+
+    defs.note_inlined(def_name, &unit);
+    unit.link(defs)
+}
+
+fn write_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?.to_vec()).ok()
+    }
+}
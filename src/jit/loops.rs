@@ -0,0 +1,664 @@
+//! Register allocation for self-tail-recursive loops.
+//!
+//! Words like `dropN` (`dup if 4 skip nop drop 5 skip swap drop 1 - dropN nop`)
+//! are loops with a counter on top of the stack. Their code keeps the top of
+//! the clac stack in r12d..r15d, and only materializes it on the clac stack
+//! around calls and slow operations, and at exit.
+//!
+//! Every jump target has a fixed number of cached values, so that all paths
+//! into it agree. It is the number of values the code after the target is
+//! about to pop anyway, so filling the cache early never pops values the
+//! program would not have popped.
+//!
+//! Straight-line words without calls, like `dup` and `nop`, are inlined.
+//! Natives with a small enough stack effect are called on cached values.
+
+use dynasmrt::{dynasm, DynamicLabel, DynasmApi, DynasmLabelApi};
+
+use super::{emit_burn, emit_stop, Checks, DefsMap, Helper, Reloc, Unit};
+use crate::profile::Specialized;
+use crate::{StackEffect, Token};
+
+type Assembler = dynasmrt::VecAssembler<dynasmrt::x64::X64Relocation>;
+
+/// Number of cached stack values
+const REGS: usize = 4;
+
+/// Longest body that is inlined
+const MAX_INLINE: usize = 16;
+
+/// Register holding the nth cached value, from the bottom
+fn reg(n: usize) -> u8 {
+    12 + n as u8
+}
+
+enum Op {
+    /// Arithmetic or stack shuffle, on cached values
+    Prim(Token),
+    /// `n pick`, with a constant `n`. Values not cached are read in place,
+    /// so errors are the same as the interpreter's
+    PickConst(usize),
+    /// Pop, and jump to the target if zero
+    If(usize),
+    /// Pop, and jump to the first target if zero, to the second otherwise
+    IfSkip(usize, usize),
+    Jump(usize),
+    /// Self tail call: back to the loop head
+    Loop,
+    /// Call to a native, with its inputs and outputs in registers
+    Native(String, StackEffect),
+    /// Anything else, run with nothing cached
+    Slow(Token),
+}
+
+impl Op {
+    /// Values popped and pushed, if the op runs on cached values
+    fn effect(&self) -> Option<(usize, usize)> {
+        use Token::*;
+
+        match self {
+            Op::Prim(token) => match token {
+                Num(_) => Some((0, 1)),
+                Add | Sub | Mul | Div | Mod | Less | Nip => Some((2, 1)),
+                Drop => Some((1, 0)),
+                Swap => Some((2, 2)),
+                Rot | RotRot => Some((3, 3)),
+                _ => None,
+            },
+            Op::PickConst(_) => Some((0, 1)),
+            Op::Native(_, effect) => Some((effect.inputs, effect.outputs)),
+            _ => None,
+        }
+    }
+}
+
+/// Lower straight-line code, two tokens at a time for `n pick`
+fn lower_straight(tokens: &[Token], checks: Checks) -> Option<Vec<Op>> {
+    use Token::*;
+
+    let mut ops = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        match (token, tokens.get(i + 1)) {
+            (Num(n), Some(Pick)) if *n > 0 => {
+                ops.push(Op::PickConst(*n as usize));
+                i += 2;
+                continue;
+            }
+            (Div | Mod, _) if checks == Checks::Full => return None,
+            (Dup, _) => ops.push(Op::PickConst(1)),
+            (Over, _) => ops.push(Op::PickConst(2)),
+            (Num(_) | Add | Sub | Mul | Div | Mod | Less | Drop | Swap | Rot | Nip | RotRot, _) => {
+                ops.push(Op::Prim(token.clone()))
+            }
+            _ => return None,
+        }
+        i += token.width();
+    }
+    Some(ops)
+}
+
+/// Body of a word that can be inlined
+pub fn inlinable(body: &[Token]) -> bool {
+    body.len() <= MAX_INLINE && lower_straight(body, Checks::Underflow).is_some()
+}
+
+struct Lowered {
+    /// Ops of each slot, or `None` for slots covered by the slot before
+    slots: Vec<Option<Vec<Op>>>,
+    /// Slots jumped to
+    targets: Vec<bool>,
+    /// Inlined words, with their body
+    inlined: Vec<(String, String)>,
+}
+
+fn lower(tokens: &[Token], def_name: &str, checks: Checks, defs: &DefsMap) -> Option<Lowered> {
+    use Token::*;
+
+    let len = tokens.len();
+    let mut targets = vec![false; len + 1];
+    for (_, to) in super::jumps(tokens)? {
+        if to > len {
+            return None;
+        }
+        targets[to] = true;
+    }
+    targets[0] = true;
+
+    let inline = |name: &str| {
+        defs.body(name)
+            .filter(|body| inlinable(body))
+            .map(|body| (lower_straight(body, checks), super::body_text(body)))
+    };
+
+    // Natives that can take their inputs from registers
+    let native = |name: &str| {
+        defs.native(name)
+            .map(|native| native.effect())
+            .filter(|effect| effect.inputs <= REGS && effect.outputs <= REGS)
+    };
+
+    // A self call only followed by words that do nothing
+    let is_tail = |i: usize| {
+        tokens[i + 1..].iter().all(|token| match token {
+            Custom(name) => matches!(inline(name), Some((Some(ops), _)) if ops.is_empty()),
+            _ => false,
+        })
+    };
+
+    let mut slots: Vec<Option<Vec<Op>>> = Vec::with_capacity(len);
+    let mut inlined = vec![];
+    let mut has_loop = false;
+    let mut i = 0;
+    while i < len {
+        let token = &tokens[i];
+        let mut width = token.width();
+        let ops = match (token, tokens.get(i + 1)) {
+            (Num(n), Some(Pick)) if *n > 0 && !targets[i + 1] => {
+                width = 2;
+                lower_straight(&tokens[i..i + 2], checks)?
+            }
+            (Num(n), Some(Skip)) if *n >= 0 && !targets[i + 1] => {
+                width = 2;
+                vec![Op::Jump(i + 2 + *n as usize)]
+            }
+            (Skip, _) | (DefBegin | DefEnd, _) => return None,
+            (If, _) => vec![Op::If(i + 4)],
+            (IfSkip(n), _) => vec![Op::IfSkip(i + 4, i + 3 + *n as usize)],
+            (Custom(name), _) if name == def_name && is_tail(i) => {
+                has_loop = true;
+                vec![Op::Loop]
+            }
+            (Custom(name), _) if native(name).is_some() => {
+                // Never matches a body, so cached code is not reused
+                inlined.push((name.clone(), String::new()));
+                vec![Op::Native(name.clone(), native(name).unwrap())]
+            }
+            (Custom(name), _) => match inline(name) {
+                Some((Some(ops), text)) => {
+                    inlined.push((name.clone(), text));
+                    ops
+                }
+                _ => vec![Op::Slow(token.clone())],
+            },
+            _ => match lower_straight(std::slice::from_ref(token), checks) {
+                Some(ops) => ops,
+                None => vec![Op::Slow(token.clone())],
+            },
+        };
+        slots.push(Some(ops));
+        for _ in 1..width {
+            slots.push(None);
+        }
+        i += width;
+    }
+    slots.truncate(len);
+
+    // Jumps into a pair lowered as one
+    if (0..len).any(|i| targets[i] && slots[i].is_none()) {
+        return None;
+    }
+
+    has_loop.then_some(Lowered {
+        slots,
+        targets,
+        inlined,
+    })
+}
+
+/// Number of values cached at a jump target: what the straight-line code
+/// after it pops from below, before it does anything else
+fn demand(slots: &[Option<Vec<Op>>], from: usize) -> usize {
+    let mut depth = 0;
+    let mut pulled = 0;
+    for ops in slots[from..].iter().flatten() {
+        for op in ops {
+            let (pops, pushes) = match op {
+                Op::If(_) | Op::IfSkip(..) => (1, 0),
+                _ => match op.effect() {
+                    Some(effect) => effect,
+                    None => return pulled.min(REGS),
+                },
+            };
+            if depth < pops {
+                pulled += pops - depth;
+                depth = pops;
+            }
+            depth = depth - pops + pushes;
+            if matches!(op, Op::If(_) | Op::IfSkip(..)) {
+                return pulled.min(REGS);
+            }
+        }
+    }
+    pulled.min(REGS)
+}
+
+struct Gen<'a> {
+    ops: &'a mut Assembler,
+    relocs: &'a mut Vec<(usize, Reloc)>,
+    checks: Checks,
+    /// Number of cached values
+    cached: usize,
+}
+
+impl Gen<'_> {
+    /// Cache one more value from the clac stack
+    fn fill(&mut self) {
+        let pop = match self.checks {
+            Checks::None => Helper::PopUnchecked,
+            _ => Helper::MustPop,
+        };
+        for n in (0..self.cached).rev() {
+            dynasm!(self.ops
+                ; mov Rd(reg(n + 1)), Rd(reg(n))
+            );
+        }
+        pop_to_eax!(self.ops, self.relocs, pop);
+        dynasm!(self.ops
+            ; mov Rd(reg(0)), eax
+        );
+        self.cached += 1;
+    }
+
+    /// Move the deepest cached value to the clac stack
+    fn spill(&mut self) {
+        dynasm!(self.ops
+            ; mov edx, Rd(reg(0))
+        );
+        push_edx!(self.ops, self.relocs);
+        for n in 1..self.cached {
+            dynasm!(self.ops
+                ; mov Rd(reg(n - 1)), Rd(reg(n))
+            );
+        }
+        self.cached -= 1;
+    }
+
+    fn normalize(&mut self, cached: usize) {
+        while self.cached > cached {
+            self.spill();
+        }
+        while self.cached < cached {
+            self.fill();
+        }
+    }
+
+    /// Get `pops` values in registers, and room for `pushes` values.
+    /// Returns the register number of the first one.
+    fn prepare(&mut self, pops: usize, pushes: usize) -> usize {
+        while self.cached < pops {
+            self.fill();
+        }
+        while self.cached - pops + pushes > REGS {
+            self.spill();
+        }
+        let base = self.cached - pops;
+        self.cached = base + pushes;
+        base
+    }
+
+    fn pick(&mut self, n: usize) {
+        if self.cached == REGS {
+            self.spill();
+        }
+        if n <= self.cached {
+            dynasm!(self.ops
+                ; mov Rd(reg(self.cached)), Rd(reg(self.cached - n))
+            );
+        } else {
+            let pick = match self.checks {
+                Checks::None => Helper::PickUnchecked,
+                // Cached values must be on the stack if the pick fails
+                _ => {
+                    self.normalize(0);
+                    Helper::MustPick
+                }
+            };
+            dynasm!(self.ops
+                ; mov rcx, rdi
+                ; mov edx, DWORD (n - self.cached) as _
+            );
+            call_helper!(self.ops, self.relocs, pick);
+            dynasm!(self.ops
+                ; mov Rd(reg(self.cached)), eax
+            );
+        }
+        self.cached += 1;
+    }
+
+    fn prim(&mut self, op: &Op) {
+        use Token::*;
+
+        if let Op::PickConst(n) = op {
+            return self.pick(*n);
+        }
+
+        let (pops, pushes) = op.effect().unwrap();
+        let base = self.prepare(pops, pushes);
+        let r = |n: usize| reg(base + n);
+        match op {
+            Op::Prim(Num(x)) => dynasm!(self.ops
+                ; mov Rd(r(0)), DWORD *x
+            ),
+            Op::Prim(Add) => dynasm!(self.ops
+                ; add Rd(r(0)), Rd(r(1))
+            ),
+            Op::Prim(Sub) => dynasm!(self.ops
+                ; sub Rd(r(0)), Rd(r(1))
+            ),
+            Op::Prim(Mul) => dynasm!(self.ops
+                ; imul Rd(r(0)), Rd(r(1))
+            ),
+            Op::Prim(Div) => dynasm!(self.ops
+                ; mov eax, Rd(r(0))
+                ; cdq
+                ; idiv Rd(r(1))
+                ; mov Rd(r(0)), eax
+            ),
+            Op::Prim(Mod) => dynasm!(self.ops
+                ; mov eax, Rd(r(0))
+                ; cdq
+                ; idiv Rd(r(1))
+                ; mov Rd(r(0)), edx
+            ),
+            Op::Prim(Less) => dynasm!(self.ops
+                ; cmp Rd(r(0)), Rd(r(1))
+                ; setl al
+                ; movzx eax, al
+                ; mov Rd(r(0)), eax
+            ),
+            Op::Prim(Drop) => {}
+            Op::Prim(Swap) => dynasm!(self.ops
+                ; xchg Rd(r(0)), Rd(r(1))
+            ),
+            Op::Prim(Rot) => dynasm!(self.ops
+                // a b c rot => b c a
+                ; mov eax, Rd(r(0))
+                ; mov Rd(r(0)), Rd(r(1))
+                ; mov Rd(r(1)), Rd(r(2))
+                ; mov Rd(r(2)), eax
+            ),
+            Op::Prim(RotRot) => dynasm!(self.ops
+                // a b c rot rot => c a b
+                ; mov eax, Rd(r(2))
+                ; mov Rd(r(2)), Rd(r(1))
+                ; mov Rd(r(1)), Rd(r(0))
+                ; mov Rd(r(0)), eax
+            ),
+            Op::Prim(Nip) => dynasm!(self.ops
+                ; mov Rd(r(0)), Rd(r(1))
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    fn native(&mut self, name: &str, effect: StackEffect) {
+        let mut base = self.prepare(effect.inputs, effect.outputs);
+        // Values below the inputs must be on the stack if the native fails
+        while base > 0 {
+            self.spill();
+            base -= 1;
+        }
+        // Inputs and outputs go through a buffer above the shadow space
+        dynasm!(self.ops
+            ; sub rsp, 16
+        );
+        for n in 0..effect.inputs {
+            dynasm!(self.ops
+                ; mov [rsp + 32 + 4 * n as i32], Rd(reg(base + n))
+            );
+        }
+        dynasm!(self.ops
+            ; mov rcx, rdi
+            ; lea r8, [rsp + 32]
+        );
+        load!(self.ops, self.relocs, rdx, Reloc::Native(name.to_string()));
+        call_helper!(self.ops, self.relocs, Helper::CallNativeInPlace);
+        for n in 0..effect.outputs {
+            dynasm!(self.ops
+                ; mov Rd(reg(base + n)), [rsp + 32 + 4 * n as i32]
+            );
+        }
+        dynasm!(self.ops
+            ; add rsp, 16
+        );
+    }
+
+    /// Take a unit of fuel before the token at `pos`. When the program has
+    /// to stop, cached values go back on the stack before the error is
+    /// raised.
+    fn burn(&mut self, def_name: &str, pos: usize) {
+        let fueled = self.ops.new_dynamic_label();
+        emit_burn(self.ops, self.relocs, fueled);
+        let saved = self.cached;
+        self.normalize(0);
+        emit_stop(self.ops, self.relocs, Some(def_name), pos);
+        dynasm!(self.ops
+            ; =>fueled
+        );
+        self.cached = saved;
+    }
+
+    /// Jump to a target, with the number of cached values it expects
+    fn jump(&mut self, cached: usize, label: DynamicLabel) {
+        let saved = self.cached;
+        self.normalize(cached);
+        dynasm!(self.ops
+            ; jmp =>label
+        );
+        self.cached = saved;
+    }
+
+    fn slow(&mut self, token: &Token) {
+        use Token::*;
+
+        self.normalize(0);
+        let full = self.checks == Checks::Full;
+        dynasm!(self.ops
+            ; mov rcx, rdi
+        );
+        match token {
+            Pow if full => {
+                call_helper!(self.ops, self.relocs, Helper::PowChecked);
+            }
+            Pow => {
+                call_helper!(self.ops, self.relocs, Helper::Pow);
+            }
+            Div => {
+                call_helper!(self.ops, self.relocs, Helper::DivChecked);
+            }
+            Mod => {
+                call_helper!(self.ops, self.relocs, Helper::ModChecked);
+            }
+            Print => {
+                call_helper!(self.ops, self.relocs, Helper::Print);
+            }
+            Quit => {
+                call_helper!(self.ops, self.relocs, Helper::Quit);
+            }
+            PickPickLess(a, b) => {
+                dynasm!(self.ops
+                    ; mov edx, DWORD *a
+                    ; mov r8d, DWORD *b
+                );
+                call_helper!(self.ops, self.relocs, Helper::PickPickLess);
+            }
+            Pick => {
+                let (pop, pick) = match self.checks {
+                    Checks::None => (Helper::PopUnchecked, Helper::PickUnchecked),
+                    _ => (Helper::MustPop, Helper::MustPick),
+                };
+                call_helper!(self.ops, self.relocs, pop);
+                dynasm!(self.ops
+                    ; mov edx, eax
+                    ; mov rcx, rdi
+                );
+                call_helper!(self.ops, self.relocs, pick);
+                dynasm!(self.ops
+                    ; mov edx, eax
+                );
+                push_edx!(self.ops, self.relocs);
+            }
+            Custom(name) => {
+                if full {
+                    call_helper!(self.ops, self.relocs, Helper::Enter);
+                    dynasm!(self.ops
+                        ; mov rcx, rdi
+                    );
+                }
+                load!(self.ops, self.relocs, rdx, Reloc::Name(name.clone()));
+                dynasm!(self.ops
+                    ; mov r8, QWORD name.len() as _
+                );
+                load!(self.ops, self.relocs, rax, Reloc::Cell(name.clone()));
+                dynasm!(self.ops
+                    ; mov rax, [rax]
+                    ; call rax
+                );
+                if full {
+                    dynasm!(self.ops
+                        ; mov rcx, rdi
+                    );
+                    call_helper!(self.ops, self.relocs, Helper::Leave);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Compile a self-tail-recursive definition, or `None` if it is not a loop
+pub fn assemble(tokens: &[Token], def_name: &str, checks: Checks, defs: &DefsMap) -> Option<Unit> {
+    let lowered = lower(tokens, def_name, checks, defs)?;
+    let len = tokens.len();
+
+    let mut ops = Assembler::new(0);
+    let mut relocs = vec![];
+
+    let labels: Vec<_> = (0..=len).map(|_| ops.new_dynamic_label()).collect();
+    let cached: Vec<_> = (0..=len)
+        .map(|i| {
+            if lowered.targets[i] && i < len {
+                demand(&lowered.slots, i)
+            } else {
+                0
+            }
+        })
+        .collect();
+
+    // Prelude. r12..r15 belong to the caller
+    dynasm!(ops
+        ; .arch x64
+        ; push rbp
+        ; mov rbp, rsp
+        ; push r12
+        ; push r13
+        ; push r14
+        ; push r15
+        ; sub rsp, 32 // Shadow space for helpers
+        ; mov rdi, rcx
+    );
+
+    let mut gen = Gen {
+        ops: &mut ops,
+        relocs: &mut relocs,
+        checks,
+        cached: 0,
+    };
+    let mut reachable = true;
+
+    for (i, slot) in lowered.slots.iter().enumerate() {
+        let Some(slot) = slot else {
+            continue;
+        };
+        if lowered.targets[i] {
+            if reachable {
+                gen.normalize(cached[i]);
+            }
+            dynasm!(gen.ops
+                ; =>labels[i]
+            );
+            gen.cached = cached[i];
+            reachable = true;
+        }
+        if !reachable {
+            continue;
+        }
+
+        for op in slot {
+            match op {
+                Op::Prim(_) | Op::PickConst(_) => gen.prim(op),
+                Op::If(target) => {
+                    let base = gen.prepare(1, 0);
+                    let not_taken = gen.ops.new_dynamic_label();
+                    dynasm!(gen.ops
+                        ; test Rd(reg(base)), Rd(reg(base))
+                        ; jnz =>not_taken
+                    );
+                    gen.jump(cached[*target], labels[*target]);
+                    dynasm!(gen.ops
+                        ; =>not_taken
+                    );
+                }
+                Op::IfSkip(zero, non_zero) => {
+                    let base = gen.prepare(1, 0);
+                    let taken = gen.ops.new_dynamic_label();
+                    dynasm!(gen.ops
+                        ; test Rd(reg(base)), Rd(reg(base))
+                        ; jnz =>taken
+                    );
+                    gen.jump(cached[*zero], labels[*zero]);
+                    dynasm!(gen.ops
+                        ; =>taken
+                    );
+                    gen.jump(cached[*non_zero], labels[*non_zero]);
+                    reachable = false;
+                }
+                Op::Jump(target) => {
+                    gen.jump(cached[*target], labels[*target]);
+                    reachable = false;
+                }
+                Op::Loop => {
+                    gen.burn(def_name, i);
+                    log::info!("Tail recursion optimization enabled for {}", def_name);
+                    gen.jump(cached[0], labels[0]);
+                    reachable = false;
+                }
+                Op::Native(name, effect) => gen.native(name, *effect),
+                Op::Slow(token) => {
+                    if let Token::Custom(_) = token {
+                        gen.burn(def_name, i);
+                    }
+                    gen.slow(token);
+                }
+            }
+            if !reachable {
+                break;
+            }
+        }
+    }
+
+    // Epilogue
+    if reachable {
+        gen.normalize(0);
+    }
+    dynasm!(ops
+        ; =>labels[len]
+        ; lea rsp, [rbp - 32]
+        ; pop r15
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; leave
+        ; ret
+    );
+
+    Some(Unit {
+        code: ops.finalize().unwrap(),
+        offsets: vec![],
+        relocs,
+        inlined: lowered.inlined,
+        specialized: Specialized::default(),
+    })
+}
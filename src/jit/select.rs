@@ -0,0 +1,192 @@
+//! Branchless conditional selects.
+//!
+//! Finds diamonds like `cond if 4 skip nop drop 2 skip swap drop nop`, where
+//! both arms only shuffle stack values, so they can be compiled to `cmov`s
+//! instead of jumps through the address table.
+//!
+//! A select checks there are enough values before it takes the condition,
+//! so when it fails, the stack is left as it was, where the interpreter
+//! would have popped some of it. `Checks::Full` promises to fail the same
+//! way as the interpreter, so it compiles diamonds to jumps instead.
+
+use crate::Token;
+
+/// Most values read under the condition. One register each.
+const MAX_INPUTS: usize = 3;
+
+/// Most values left by an arm
+const MAX_OUTPUTS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Val {
+    /// The nth value below the condition, 0 being the closest
+    Input(usize),
+    Const(i32),
+}
+
+/// Stack effect of a branch-free sequence of shuffles
+struct Shuffle {
+    stack: Vec<Val>,
+    inputs: usize,
+}
+
+impl Shuffle {
+    fn run(tokens: &[Token]) -> Option<Shuffle> {
+        use Token::*;
+
+        let mut shuffle = Shuffle {
+            stack: vec![],
+            inputs: 0,
+        };
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            match (token, tokens.get(i + 1)) {
+                (Num(n), Some(Pick)) if *n > 0 => {
+                    let v = shuffle.pick(*n as usize);
+                    shuffle.stack.push(v);
+                    i += 2;
+                    continue;
+                }
+                (Num(n), _) => shuffle.stack.push(Val::Const(*n)),
+                (Drop, _) => {
+                    shuffle.pop();
+                }
+                (Swap, _) => {
+                    let a = shuffle.pop();
+                    let b = shuffle.pop();
+                    shuffle.stack.extend([a, b]);
+                }
+                (Rot, _) => {
+                    // a b c rot => b c a
+                    let c = shuffle.pop();
+                    let b = shuffle.pop();
+                    let a = shuffle.pop();
+                    shuffle.stack.extend([b, c, a]);
+                }
+                (Dup, _) => {
+                    let v = shuffle.pick(1);
+                    shuffle.stack.push(v);
+                }
+                (Over, _) => {
+                    let v = shuffle.pick(2);
+                    shuffle.stack.push(v);
+                }
+                (Nip, _) => {
+                    let a = shuffle.pop();
+                    shuffle.pop();
+                    shuffle.stack.push(a);
+                }
+                (RotRot, _) => {
+                    // a b c rot rot => c a b
+                    let c = shuffle.pop();
+                    let b = shuffle.pop();
+                    let a = shuffle.pop();
+                    shuffle.stack.extend([c, a, b]);
+                }
+                _ => return None,
+            }
+            i += token.width();
+        }
+        Some(shuffle)
+    }
+
+    /// Take one more value from below
+    fn pull(&mut self) {
+        self.stack.insert(0, Val::Input(self.inputs));
+        self.inputs += 1;
+    }
+
+    fn pop(&mut self) -> Val {
+        if self.stack.is_empty() {
+            self.pull();
+        }
+        self.stack.pop().unwrap()
+    }
+
+    fn pick(&mut self, n: usize) -> Val {
+        while self.stack.len() < n {
+            self.pull();
+        }
+        self.stack[self.stack.len() - n]
+    }
+}
+
+pub struct Select {
+    /// Values read under the condition
+    pub inputs: usize,
+    /// Values left when the condition is not zero, bottom first
+    pub then_out: Vec<Val>,
+    /// Values left when the condition is zero, bottom first
+    pub else_out: Vec<Val>,
+    /// Where both arms continue
+    pub join: usize,
+}
+
+/// Constant `n skip` at `i`
+fn const_skip(tokens: &[Token], i: usize) -> Option<usize> {
+    match tokens.get(i..i + 2)? {
+        [Token::Num(n), Token::Skip] if *n >= 0 => Some(*n as usize),
+        _ => None,
+    }
+}
+
+/// Find a select starting with the `if` at `i`
+pub fn find(tokens: &[Token], i: usize) -> Option<Select> {
+    use Token::*;
+
+    // (then arm, else arm, join)
+    let (then_arm, else_arm, join) = match tokens[i] {
+        // cond if 4 skip _ <else> m skip <then>
+        IfSkip(n) => {
+            let then_start = i + 3 + n as usize;
+            let else_end = then_start.checked_sub(2)?;
+            let m = const_skip(tokens, else_end)?;
+            (then_start..then_start + m, i + 4..else_end, then_start + m)
+        }
+        // cond if <then> m skip <else>
+        If if const_skip(tokens, i + 2).is_some() => {
+            let m = const_skip(tokens, i + 2)?;
+            (i + 1..i + 2, i + 4..i + 4 + m, i + 4 + m)
+        }
+        // cond if <then>
+        If => (i + 1..i + 4, i + 4..i + 4, i + 4),
+        _ => return None,
+    };
+    if join > tokens.len() || else_arm.start > else_arm.end {
+        return None;
+    }
+
+    // Nothing else may jump into the diamond
+    let diamond = i..join;
+    if super::jumps(tokens)?
+        .iter()
+        .any(|(from, to)| !diamond.contains(from) && *to > i && *to < join)
+    {
+        return None;
+    }
+
+    let mut then_shuffle = Shuffle::run(&tokens[then_arm])?;
+    let mut else_shuffle = Shuffle::run(&tokens[else_arm])?;
+
+    // Both arms read the same values
+    let inputs = then_shuffle.inputs.max(else_shuffle.inputs);
+    while then_shuffle.inputs < inputs {
+        then_shuffle.pull();
+    }
+    while else_shuffle.inputs < inputs {
+        else_shuffle.pull();
+    }
+
+    let outputs = then_shuffle.stack.len();
+    if outputs != else_shuffle.stack.len() || inputs > MAX_INPUTS || outputs > MAX_OUTPUTS {
+        return None;
+    }
+
+    Some(Select {
+        inputs,
+        then_out: then_shuffle.stack,
+        else_out: else_shuffle.stack,
+        join,
+    })
+}
@@ -1,17 +1,86 @@
+pub mod aot;
+pub mod backend;
+pub mod bytecode;
+pub mod clacb;
 mod defs;
+mod error;
+pub mod ffi;
+#[cfg(feature = "jit")]
 pub mod jit;
-use std::collections::HashMap;
+mod library;
+pub mod native;
+pub mod peephole;
+pub mod profile;
+pub mod reach;
+mod snapshot;
+mod step;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
+pub use backend::{ExecutionBackend, InterpreterBackend};
 pub use defs::*;
+pub use error::{ClacError, Position};
+pub use library::Library;
+pub use native::StackEffect;
+pub use step::Step;
 
 pub struct State {
-    defs: HashMap<String, Queue<Token>>,
-    jitted: jit::DefsMap,
+    /// Taken out while `eval` runs
+    backend: Option<Box<dyn ExecutionBackend>>,
+    /// Depth of nested jitted calls, with `Checks::Full`
+    #[cfg(feature = "jit")]
+    depth: usize,
+    /// Stack pointer to unwind jitted code to when it raises an error
+    #[cfg(feature = "jit")]
+    escape: usize,
+    /// The error raised by jitted code
+    #[cfg(feature = "jit")]
+    raised: Option<ClacError>,
     return_stack: ReturnStack,
     stack: TheStack,
     pub queue: TheQueue,
+    /// Where `print` writes to
+    output: Box<dyn Write + Send>,
+    /// Tokens a compiled top-level chunk skipped past its end
+    pending_skip: usize,
+    /// Set by backends when `quit` ran, for `eval` to stop
+    quit: bool,
+    profile: Option<profile::Profile>,
+    /// Definitions jitted code found hot, with the profile they gathered
+    #[cfg(feature = "jit")]
+    hot: Vec<(String, Arc<std::sync::Mutex<profile::WordProfile>>)>,
+    /// Definitions being interpreted
+    frames: Vec<String>,
+    /// Tokens, or jitted calls and loop iterations, left to run
+    fuel: u64,
+    /// Whether `fuel` was set, rather than left unlimited
+    fuel_limited: bool,
+    /// Most values the data stack may hold
+    max_stack: usize,
+    /// Most calls that may be nested
+    max_return_stack: usize,
+    /// Set from outside to stop the running evaluation
+    interrupt: Arc<AtomicBool>,
+    /// Words to call when the backend does not know them
+    library: Option<Arc<Library>>,
+    /// Depths of the return stack at which library words are interpreted
+    library_frames: Vec<usize>,
+    /// The flag in `interrupt`, for jitted code to read
+    #[cfg(feature = "jit")]
+    interrupt_flag: FlagAddress,
 }
 
+/// Address of the flag in an `Arc`, which stays put when the `State`
+/// holding the `Arc` moves
+#[cfg(feature = "jit")]
+#[repr(transparent)]
+struct FlagAddress(*const AtomicBool);
+
+// Only read through by jitted code, while the `Arc` is alive
+#[cfg(feature = "jit")]
+unsafe impl Send for FlagAddress {}
+
 impl Default for State {
     fn default() -> Self {
         Self::new()
@@ -19,53 +88,229 @@ impl Default for State {
 }
 
 impl State {
+    /// A state that interprets everything
     pub fn new() -> Self {
+        Self::with_backend(Box::<InterpreterBackend>::default())
+    }
+
+    pub fn with_backend(backend: Box<dyn ExecutionBackend>) -> Self {
+        let interrupt = Arc::new(AtomicBool::new(false));
         Self {
-            defs: HashMap::new(),
-            jitted: jit::DefsMap::new(),
+            backend: Some(backend),
+            #[cfg(feature = "jit")]
+            depth: 0,
+            #[cfg(feature = "jit")]
+            escape: 0,
+            #[cfg(feature = "jit")]
+            raised: None,
             return_stack: ReturnStack::new(),
             stack: TheStack::new(),
             queue: TheQueue::new(),
+            output: Box::new(std::io::stdout()),
+            pending_skip: 0,
+            quit: false,
+            profile: None,
+            #[cfg(feature = "jit")]
+            hot: Vec::new(),
+            frames: Vec::new(),
+            fuel: u64::MAX,
+            fuel_limited: false,
+            max_stack: usize::MAX,
+            max_return_stack: usize::MAX,
+            #[cfg(feature = "jit")]
+            interrupt_flag: FlagAddress(Arc::as_ptr(&interrupt)),
+            interrupt,
+            library: None,
+            library_frames: Vec::new(),
+        }
+    }
+
+    /// Send what programs print somewhere else than stdout. Messages about
+    /// compiling and defining words go to the `log` crate instead.
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.output = output;
+    }
+
+    /// Gather a profile of definitions defined from now on. In jit mode,
+    /// hot definitions are recompiled with it. Enabling it again starts over,
+    /// and what code defined before gathers is no longer used.
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(profile::Profile::new());
+    }
+
+    pub fn profile(&self) -> Option<&profile::Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Definition and position of the token just taken from the queue, if
+    /// it is in an interpreted definition and profiling is on
+    fn site(&self) -> Option<(String, usize)> {
+        self.profile.as_ref()?;
+        match self.position() {
+            Position::Word(name, pos) => Some((name, pos)),
+            Position::TopLevel => None,
+        }
+    }
+
+    /// Position of the token just taken from the queue
+    fn position(&self) -> Position {
+        let Some(name) = self.frames.last() else {
+            return Position::TopLevel;
+        };
+        match &self.queue {
+            TheQueue::Def(_, next) => Position::Word(name.clone(), next - 1),
+            _ => Position::TopLevel,
+        }
+    }
+
+    /// Limit how much later evaluations may run, or lift the limit with
+    /// `None`. Interpreted tokens, bytecode ops, and jitted calls and loop
+    /// iterations each take a unit. Running out is a `FuelExhausted` error,
+    /// and the budget is not refilled until it is set again.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel.unwrap_or(u64::MAX);
+        self.fuel_limited = fuel.is_some();
+    }
+
+    /// Fuel left, if it was limited
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel_limited.then_some(self.fuel)
+    }
+
+    /// Limit the values the data stack may hold, or lift the limit with
+    /// `None`. Going over it is a `StackOverflow` error.
+    pub fn set_max_stack(&mut self, max: Option<usize>) {
+        self.max_stack = max.unwrap_or(usize::MAX);
+    }
+
+    /// Limit how deep calls may nest, or lift the limit with `None`. Going
+    /// over it is a `ReturnStackOverflow` error. Jitted code only counts
+    /// calls with `Checks::Full`.
+    pub fn set_max_return_stack(&mut self, max: Option<usize>) {
+        self.max_return_stack = max.unwrap_or(usize::MAX);
+    }
+
+    fn check_stack(&self) -> Result<(), ClacError> {
+        if self.stack.len() > self.max_stack {
+            return Err(ClacError::StackOverflow);
         }
+        Ok(())
+    }
+
+    /// Check there is room for one more nested call, `depth` deep already
+    fn check_return_stack(&self, depth: usize) -> Result<(), ClacError> {
+        if depth >= self.max_return_stack {
+            return Err(ClacError::ReturnStackOverflow);
+        }
+        Ok(())
+    }
+
+    /// A flag that stops the running evaluation with `Interrupted` when it
+    /// is set, from another thread or a signal handler. It is checked where
+    /// fuel is taken, and cleared when it stops something.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    /// Take a unit of fuel, or report where the program has to stop
+    fn burn(&mut self, at: impl FnOnce(&Self) -> Position) -> Result<(), ClacError> {
+        if self.fuel == 0 {
+            return Err(ClacError::FuelExhausted(at(self)));
+        }
+        self.fuel -= 1;
+        if self.interrupt.load(Ordering::Relaxed) && self.interrupt.swap(false, Ordering::Relaxed) {
+            return Err(ClacError::Interrupted(at(self)));
+        }
+        Ok(())
+    }
+
+    /// Parse and run source, and return the stack after it, bottom first.
+    /// A program that ran `quit` stops there, and is not an error.
+    pub fn run_str(&mut self, input: &str) -> Result<&[i32], ClacError> {
+        self.run_str_outcome(input).map(|(_, stack)| stack)
+    }
+
+    /// Same as `run_str`, but also tell whether the program ran `quit`
+    pub fn run_str_outcome(&mut self, input: &str) -> Result<(Outcome, &[i32]), ClacError> {
+        self.parse(input);
+        let outcome = eval(self)?;
+        Ok((outcome, self.stack()))
+    }
+
+    /// The data stack, bottom first
+    pub fn stack(&self) -> &[i32] {
+        self.stack.as_slice()
+    }
+
+    /// Push values, in order, so the last one ends up on top
+    pub fn push_values(&mut self, values: &[i32]) {
+        for value in values {
+            self.stack.push(*value);
+        }
+    }
+
+    pub fn clear_stack(&mut self) {
+        self.stack.clear();
+    }
+
+    /// Make a Rust closure callable as a word. It gets the `inputs` of the
+    /// stack effect, bottom first, and returns its `outputs`. If it panics,
+    /// the program fails with `ClacError::Native` on every backend.
+    pub fn register_native<F>(&mut self, name: &str, effect: StackEffect, f: F)
+    where
+        F: FnMut(&[i32]) -> Result<Vec<i32>, ClacError> + Send + 'static,
+    {
+        let native = Arc::new(native::Native::new(name, effect, Box::new(f)));
+        let mut backend = self.backend.take().expect("eval is not reentrant");
+        backend.define_native(self, name, native);
+        self.backend = Some(backend);
+    }
+
+    /// Names of all definitions, sorted
+    pub fn definitions(&self) -> Vec<String> {
+        let mut names = match &self.backend {
+            Some(backend) => backend.definitions(self),
+            None => vec![],
+        };
+        names.sort();
+        names
     }
 
     fn is_end(&self) -> bool {
         self.queue.is_empty() && self.return_stack.is_empty()
     }
 
-    extern "win64" fn must_pop(&mut self) -> i32 {
-        self.stack.pop().unwrap_or_else(|| {
-            eprintln!("Stack underflow");
-            std::process::exit(1);
-        })
+    fn must_pop(&mut self) -> Result<i32, ClacError> {
+        self.stack.pop().ok_or(ClacError::StackUnderflow)
     }
 
-    extern "win64" fn push(&mut self, value: i32) {
+    #[cfg(feature = "jit")]
+    fn push(&mut self, value: i32) {
         self.stack.push(value);
     }
 
-    extern "win64" fn must_pick(&self, n: usize) -> i32 {
-        if let Some(v) = self.stack.pick(n - 1) {
-            *v
-        } else {
-            eprintln!("Index out of bounds");
-            std::process::exit(1);
-        }
+    fn must_pick(&self, n: usize) -> Result<i32, ClacError> {
+        n.checked_sub(1)
+            .and_then(|n| self.stack.pick(n))
+            .copied()
+            .ok_or(ClacError::IndexOutOfBounds)
+    }
+
+    fn print(&mut self, n: i32) -> Result<(), ClacError> {
+        writeln!(self.output, "{}", n).map_err(|e| ClacError::Output(e.to_string()))
     }
 
-    fn must_pop_queue(&mut self) -> Token {
-        self.queue.pop().unwrap_or_else(|| {
-            eprintln!("Queue underflow");
-            std::process::exit(1);
-        })
+    fn must_pop_queue(&mut self) -> Result<Token, ClacError> {
+        self.queue.pop().ok_or(ClacError::QueueUnderflow)
     }
 
-    pub fn print_stack(&self) {
-        print!("Stack: ");
+    /// Write the stack to the output, bottom first
+    pub fn print_stack(&mut self) -> Result<(), ClacError> {
+        let mut line = String::from("Stack: ");
         for n in self.stack.iter() {
-            print!("{} ", n);
+            line.push_str(&format!("{} ", n));
         }
-        println!();
+        writeln!(self.output, "{}", line).map_err(|e| ClacError::Output(e.to_string()))
     }
 
     pub fn parse(&mut self, input: &str) {
@@ -75,13 +320,54 @@ impl State {
         }
     }
 
+    /// Take top-level tokens up to the next definition
+    pub(crate) fn take_chunk(&mut self) -> Queue<Token> {
+        let mut chunk = Queue::new();
+        while let Some(token) = self.queue.peek() {
+            if matches!(token, Token::DefBegin | Token::DefEnd) {
+                break;
+            }
+            chunk.push(self.queue.pop().unwrap());
+        }
+        chunk
+    }
+
+    /// Step over the tokens a superinstruction covers
+    fn skip_covered(&mut self, token: &Token) -> Result<(), ClacError> {
+        for _ in 1..token.width() {
+            self.must_pop_queue()?;
+        }
+        Ok(())
+    }
+
     fn after_return(&mut self) {
         // return stack should not be empty
+        if self.in_library() {
+            self.library_frames.pop();
+        }
         self.queue = self.return_stack.pop().unwrap();
+        self.frames.pop();
+    }
+
+    /// Drop whatever was left to run when an error or `quit` stopped the
+    /// program. The data stack is kept as it was.
+    fn abort(&mut self) {
+        self.quit = false;
+        self.queue = TheQueue::new();
+        self.return_stack = ReturnStack::new();
+        self.frames.clear();
+        self.library_frames.clear();
+        self.pending_skip = 0;
+        #[cfg(feature = "jit")]
+        {
+            self.depth = 0;
+        }
     }
 }
 
-fn parse(input: &str) -> TheQueue {
+/// Split source into tokens, as `State::parse` queues them. Anything that
+/// is not a number or a builtin is a word.
+pub fn parse(input: &str) -> TheQueue {
     let mut queue = TheQueue::new();
     for token in input.split_whitespace() {
         match token {
@@ -114,17 +400,28 @@ fn parse(input: &str) -> TheQueue {
     queue
 }
 
-macro_rules !error {
-    ($($arg:tt)*) => {
-        {
-            eprintln!($($arg)*);
-            std::process::exit(1);
-        }
+/// How an evaluation ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Everything in the queue ran
+    Done,
+    /// The program ran `quit`
+    Quit,
+}
+
+/// Clac intrepreter. Runs everything in the queue, or stops at `quit` or at
+/// the first error with the rest of the queue dropped.
+pub fn eval(state: &mut State) -> Result<Outcome, ClacError> {
+    let mut backend = state.backend.take().expect("eval is not reentrant");
+    let result = run(state, backend.as_mut());
+    state.backend = Some(backend);
+    if result != Ok(Outcome::Done) {
+        state.abort();
     }
+    result
 }
 
-/// Clac intrepreter
-pub fn eval(state: &mut State, jit: bool) {
+fn run(state: &mut State, backend: &mut dyn ExecutionBackend) -> Result<Outcome, ClacError> {
     use Token::*;
     while !state.is_end() {
         if state.queue.is_empty() {
@@ -134,168 +431,265 @@ pub fn eval(state: &mut State, jit: bool) {
             }
         }
 
-        let token = state.queue.pop().unwrap();
-
-        match token {
-            Add => {
-                let a = state.must_pop();
-                let b = state.must_pop();
-                state.stack.push(a + b);
-            }
-            Sub => {
-                let a = state.must_pop();
-                let b = state.must_pop();
-                state.stack.push(b - a);
+        if state.return_stack.is_empty()
+            && !matches!(state.queue.peek(), Some(DefBegin | DefEnd))
+            && backend.run_chunk(state)?
+        {
+            if state.quit {
+                return Ok(Outcome::Quit);
             }
-            Mul => {
-                let a = state.must_pop();
-                let b = state.must_pop();
-                state.stack.push(a * b);
+            for _ in 0..std::mem::take(&mut state.pending_skip) {
+                state.must_pop_queue()?;
             }
-            Div => {
-                let a = state.must_pop();
-                let b = state.must_pop();
+            continue;
+        }
 
-                if a == 0 {
-                    error!("Division by zero");
-                }
-                if a == -1 && b == i32::MIN {
-                    error!("Overflow");
-                }
+        let token = state.queue.pop().unwrap();
+        if let Event::Quit = exec(state, backend, token)? {
+            return Ok(Outcome::Quit);
+        }
+    }
+    Ok(Outcome::Done)
+}
+
+/// What running a token did, besides changing the stack
+enum Event {
+    Ran,
+    /// Jumped over this many tokens
+    Skipped(usize),
+    /// Called a word, which the backend either ran or set up to be run
+    Called,
+    Defined(String),
+    Quit,
+}
+
+/// Run a token just taken from the queue
+fn exec(
+    state: &mut State,
+    backend: &mut dyn ExecutionBackend,
+    token: Token,
+) -> Result<Event, ClacError> {
+    use Token::*;
+    state.burn(State::position)?;
+    match token {
+        Add => {
+            let a = state.must_pop()?;
+            let b = state.must_pop()?;
+            state.stack.push(a + b);
+        }
+        Sub => {
+            let a = state.must_pop()?;
+            let b = state.must_pop()?;
+            state.stack.push(b - a);
+        }
+        Mul => {
+            let a = state.must_pop()?;
+            let b = state.must_pop()?;
+            state.stack.push(a * b);
+        }
+        Div => {
+            let a = state.must_pop()?;
+            let b = state.must_pop()?;
 
-                state.stack.push(b / a);
+            if a == 0 {
+                return Err(ClacError::DivisionByZero);
+            }
+            if a == -1 && b == i32::MIN {
+                return Err(ClacError::Overflow);
             }
-            Mod => {
-                let a = state.must_pop();
-                let b = state.must_pop();
 
-                if a == 0 {
-                    error!("Division by zero");
-                }
-                if a == -1 && b == i32::MIN {
-                    error!("Overflow");
-                }
+            state.stack.push(b / a);
+        }
+        Mod => {
+            let a = state.must_pop()?;
+            let b = state.must_pop()?;
 
-                state.stack.push(b % a);
+            if a == 0 {
+                return Err(ClacError::DivisionByZero);
             }
-            Pow => {
-                let a = state.must_pop();
-                let b = state.must_pop();
-                if a < 0 {
-                    error!("Negative exponent");
-                }
-                state.stack.push(b.pow(a as u32));
+            if a == -1 && b == i32::MIN {
+                return Err(ClacError::Overflow);
             }
-            Less => {
-                let a = state.must_pop();
-                let b = state.must_pop();
-                state.stack.push(if b < a { 1 } else { 0 });
+
+            state.stack.push(b % a);
+        }
+        Pow => {
+            let a = state.must_pop()?;
+            let b = state.must_pop()?;
+            if a < 0 {
+                return Err(ClacError::NegativeExponent);
             }
-            Num(num) => state.stack.push(num),
-            Swap => {
-                let a = state.must_pop();
-                let b = state.must_pop();
-                state.stack.push(a);
-                state.stack.push(b);
+            state.stack.push(b.pow(a as u32));
+        }
+        Less => {
+            let a = state.must_pop()?;
+            let b = state.must_pop()?;
+            state.stack.push(if b < a { 1 } else { 0 });
+        }
+        Num(num) => state.stack.push(num),
+        Swap => {
+            let a = state.must_pop()?;
+            let b = state.must_pop()?;
+            state.stack.push(a);
+            state.stack.push(b);
+        }
+        Rot => {
+            let a = state.must_pop()?;
+            let b = state.must_pop()?;
+            let c = state.must_pop()?;
+            state.stack.push(b);
+            state.stack.push(a);
+            state.stack.push(c);
+        }
+        Pick => {
+            // Use iter
+            let n = state.must_pop()?;
+            if n <= 0 {
+                return Err(ClacError::InvalidIndex);
             }
-            Rot => {
-                let a = state.must_pop();
-                let b = state.must_pop();
-                let c = state.must_pop();
-                state.stack.push(b);
-                state.stack.push(a);
-                state.stack.push(c);
+            let n = n as usize;
+            state.stack.push(state.must_pick(n)?);
+        }
+        If => {
+            let cond = state.must_pop()?;
+            if let Some((name, pos)) = state.site() {
+                let profile = state.profile.as_mut().unwrap();
+                profile.word_mut(&name).record_if(pos, cond);
             }
-            Pick => {
-                // Use iter
-                let n = state.must_pop();
-                if n <= 0 {
-                    error!("Invalid index");
+            if cond == 0 {
+                // Skip next three
+                for _ in 0..3 {
+                    state.must_pop_queue()?;
                 }
-                let n = n as usize;
-                state.stack.push(state.must_pick(n));
+                return Ok(Event::Skipped(3));
             }
-            If => {
-                let cond = state.must_pop();
-                if cond == 0 {
-                    // Skip next three
-                    for _ in 0..3 {
-                        state.must_pop_queue();
-                    }
+        }
+        Skip => {
+            let n = state.must_pop()?;
+            if let Some((name, pos)) = state.site() {
+                // Only skips without a constant count
+                let before = pos.checked_sub(1).and_then(|i| state.queue.def_token(i));
+                if !matches!(before, Some(Num(_))) {
+                    let profile = state.profile.as_mut().unwrap();
+                    profile.word_mut(&name).record_skip(pos, n);
                 }
             }
-            Skip => {
-                let n = state.must_pop();
-                if n < 0 {
-                    error!("Negative skip");
-                }
-                for _ in 0..n {
-                    state.must_pop_queue();
-                }
+            if n < 0 {
+                return Err(ClacError::NegativeSkip);
             }
-            Print => {
-                let n = state.must_pop();
-                println!("{}", n);
+            for _ in 0..n {
+                state.must_pop_queue()?;
             }
-            Drop => {
-                state.must_pop();
+            return Ok(Event::Skipped(n as usize));
+        }
+        Print => {
+            let n = state.must_pop()?;
+            state.print(n)?;
+        }
+        Drop => {
+            state.must_pop()?;
+        }
+        Dup => {
+            state.stack.push(state.must_pick(1)?);
+            state.skip_covered(&token)?;
+        }
+        Over => {
+            state.stack.push(state.must_pick(2)?);
+            state.skip_covered(&token)?;
+        }
+        Nip => {
+            let a = state.must_pop()?;
+            state.must_pop()?;
+            state.stack.push(a);
+            state.skip_covered(&token)?;
+        }
+        RotRot => {
+            // a b c rot rot => c a b
+            let c = state.must_pop()?;
+            let b = state.must_pop()?;
+            let a = state.must_pop()?;
+            state.stack.push(c);
+            state.stack.push(a);
+            state.stack.push(b);
+            state.skip_covered(&token)?;
+        }
+        PickPickLess(a, b) => {
+            let x = state.must_pick(a as usize)?;
+            state.stack.push(x);
+            let y = state.must_pick(b as usize)?;
+            state.must_pop()?;
+            state.stack.push(if x < y { 1 } else { 0 });
+            state.skip_covered(&token)?;
+        }
+        IfSkip(n) => {
+            let cond = state.must_pop()?;
+            if let Some((name, pos)) = state.site() {
+                let profile = state.profile.as_mut().unwrap();
+                profile.word_mut(&name).record_if(pos, cond);
             }
-            Quit => std::process::exit(0),
-            DefBegin => {
-                let mut def = Queue::new();
-
-                loop {
-                    let token = state.must_pop_queue();
-                    if token == DefEnd {
-                        break;
-                    }
-                    def.push(token);
+            state.skip_covered(&token)?;
+            if cond != 0 {
+                // Skip the n tokens after the covered `skip _`, the
+                // last of which was covered
+                for _ in 1..n {
+                    state.must_pop_queue()?;
                 }
+                return Ok(Event::Skipped(n as usize));
+            }
+            // Like `if`, which jumps over `n skip _`
+            return Ok(Event::Skipped(3));
+        }
+        Quit => return Ok(Event::Quit),
+        DefBegin => {
+            let mut def = Queue::new();
 
-                if def.is_empty() {
-                    error!("Empty definition");
-                }
-                if let Custom(name) = &def.pop().unwrap() {
-                    if name == "comment" {
-                        continue;
-                    }
-                    if jit {
-                        println!("Compiling {}...", name);
-                        let code = jit::compile(def, Some(name), &mut state.jitted);
-                        state.jitted.fill(name, code);
-                    } else {
-                        state.defs.insert(name.clone(), def);
-                    }
-                    println!("Defined {}", name);
-                } else {
-                    error!("Invalid definition");
+            loop {
+                let token = state.must_pop_queue()?;
+                if token == DefEnd {
+                    break;
                 }
+                def.push(token);
             }
-            DefEnd => error!("Unexpected definition end"),
-            Custom(name) => {
-                if let Some(def) = state.defs.get(&name) {
-                    // Move the queue to the return stack
-                    state.return_stack.push(state.queue.take());
-                    state.queue.become_iter(def);
-                } else {
-                    if jit {
-                        if let Some(code) = state.jitted.get_second(&name) {
-                            jit::take_care_of_regs(code, state);
-                            // code(state);
-                            continue;
-                        }
-                    }
-                    error!("Unknown definition: {}", name);
+
+            if def.is_empty() {
+                return Err(ClacError::EmptyDefinition);
+            }
+            if let Custom(name) = def.pop().unwrap() {
+                if name == "comment" {
+                    return Ok(Event::Ran);
                 }
+                // Backends only compile bodies without definitions
+                if def.iter().any(|token| *token == DefBegin) {
+                    return Err(ClacError::InvalidDefinition);
+                }
+                let def = peephole::optimize(reach::prune(def, &name));
+                backend.define(state, &name, def);
+                log::info!("Defined {}", name);
+                return Ok(Event::Defined(name));
+            } else {
+                return Err(ClacError::InvalidDefinition);
             }
         }
+        DefEnd => return Err(ClacError::UnexpectedDefinitionEnd),
+        Custom(name) => {
+            // Library code only sees the library's words
+            let called = if state.in_library() {
+                state.call_library(&name)?
+            } else {
+                let called = backend.call(state, &name);
+                backend.after_call(state);
+                called? || state.call_library(&name)?
+            };
+            if !called {
+                return Err(ClacError::UnknownDefinition(name));
+            }
+            if state.quit {
+                return Ok(Event::Quit);
+            }
+            state.check_stack()?;
+            return Ok(Event::Called);
+        }
     }
+    state.check_stack()?;
+    Ok(Event::Ran)
 }
-
-// Quick eval: give a queue and get the stack
-// pub fn quick_eval(queue: TheQueue) -> TheStack {
-//     let mut state = State::new();
-//     state.queue = queue;
-//     eval(&mut state);
-//     state.stack
-// }
@@ -0,0 +1,70 @@
+//! Definitions compiled once, and shared by many states.
+//!
+//! A `Library` owns a backend holding its definitions, and is frozen once
+//! built: nothing can be defined in it any more, so states on many threads
+//! can call its words at the same time. Each state keeps its own stacks,
+//! queue, limits and definitions. The state's definitions shadow the
+//! library's in the state's own code, while words of the library only ever
+//! call each other.
+
+use std::sync::Arc;
+
+use crate::{run, ClacError, ExecutionBackend, State};
+
+pub struct Library {
+    backend: Box<dyn ExecutionBackend + Sync>,
+}
+
+impl Library {
+    /// Define the words in `source` with a backend. Top-level code in it
+    /// runs once, on a state of its own that is then dropped.
+    pub fn new(
+        mut backend: Box<dyn ExecutionBackend + Sync>,
+        source: &str,
+    ) -> Result<Library, ClacError> {
+        let mut state = State::new();
+        state.parse(source);
+        run(&mut state, backend.as_mut())?;
+        Ok(Library { backend })
+    }
+
+    /// Names of the words in the library, sorted
+    pub fn definitions(&self) -> Vec<String> {
+        let mut names = self.backend.definitions(&State::new());
+        names.sort();
+        names
+    }
+
+    pub(crate) fn call(&self, state: &mut State, name: &str) -> Result<bool, ClacError> {
+        self.backend.call(state, name)
+    }
+}
+
+impl State {
+    /// A state that calls the words of a library, and interprets its own
+    /// code. Words of the library call each other through its backend.
+    pub fn with_library(library: Arc<Library>) -> Self {
+        let mut state = Self::new();
+        state.library = Some(library);
+        state
+    }
+
+    /// Call a word of the library, for words the backend does not know
+    pub(crate) fn call_library(&mut self, name: &str) -> Result<bool, ClacError> {
+        let Some(library) = self.library.clone() else {
+            return Ok(false);
+        };
+        let depth = self.return_stack.len();
+        let called = library.call(self, name)?;
+        // An interpreting library leaves the body to run on this state
+        if self.return_stack.len() > depth {
+            self.library_frames.push(self.return_stack.len());
+        }
+        Ok(called)
+    }
+
+    /// Whether the word being interpreted is one of the library's
+    pub(crate) fn in_library(&self) -> bool {
+        self.library_frames.last() == Some(&self.return_stack.len())
+    }
+}
@@ -1,4 +1,6 @@
-use std::{io::Write, path::PathBuf};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use argh::FromArgs;
 
@@ -9,49 +11,304 @@ struct Args {
     #[argh(switch, short = 'j')]
     jit: bool,
 
+    /// run definitions as bytecode
+    #[argh(switch)]
+    bytecode: bool,
+
+    /// guards in jitted code: none, underflow (default) or full
+    #[cfg(feature = "jit")]
+    #[argh(option, default = "clacjit::jit::Checks::Underflow")]
+    jit_checks: clacjit::jit::Checks,
+
+    /// directory to cache compiled definitions in
+    #[cfg(feature = "jit")]
+    #[argh(option)]
+    cache: Option<PathBuf>,
+
+    /// profile definitions, and recompile hot ones with their profile
+    #[argh(switch)]
+    profile: bool,
+
+    /// stop each evaluation after this many steps: tokens when
+    /// interpreting, ops in bytecode, and calls and loop iterations in jit
+    #[argh(option)]
+    max_steps: Option<u64>,
+
+    /// most values the data stack may hold
+    #[argh(option)]
+    max_stack: Option<usize>,
+
+    /// most calls that may nest; jitted code needs --jit-checks full
+    #[argh(option)]
+    max_return_stack: Option<usize>,
+
+    /// load definitions and the stack saved with --save before the files
+    #[argh(option)]
+    restore: Option<PathBuf>,
+
+    /// save definitions and the stack after evaluating the files, and again
+    /// when the REPL ends
+    #[argh(option)]
+    save: Option<PathBuf>,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+
     /// input files
     #[argh(positional)]
     files: Vec<PathBuf>,
 }
 
-fn main() {
-    let args: Args = argh::from_env();
-    if args.jit {
-        println!("=== JIT enabled ===");
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Build(Build),
+    CompileBytecode(CompileBytecode),
+}
+
+#[derive(Debug, FromArgs)]
+/// Compile clac programs into a standalone executable
+#[argh(subcommand, name = "build")]
+struct Build {
+    /// output executable
+    #[argh(option, short = 'o')]
+    output: PathBuf,
+
+    /// input files
+    #[argh(positional)]
+    files: Vec<PathBuf>,
+}
+
+#[derive(Debug, FromArgs)]
+/// Compile clac programs into a bytecode file, to be run without parsing
+#[argh(subcommand, name = "compile-bytecode")]
+struct CompileBytecode {
+    /// output file, usually ending in .clacb
+    #[argh(option, short = 'o')]
+    output: PathBuf,
+
+    /// input files
+    #[argh(positional)]
+    files: Vec<PathBuf>,
+}
+
+/// Prints messages about compiling and defining words to stderr, apart
+/// from what programs print
+struct Chatter;
+
+impl log::Log for Chatter {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
     }
 
-    // Check if files are accessible
-    for file in &args.files {
+    fn log(&self, record: &log::Record) {
+        match record.level() {
+            log::Level::Info => eprintln!("{}", record.args()),
+            log::Level::Warn => eprintln!("Warning: {}", record.args()),
+            log::Level::Error => eprintln!("Error: {}", record.args()),
+            _ => {}
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static CHATTER: Chatter = Chatter;
+
+fn check_files(files: &[PathBuf]) {
+    for file in files {
         if !file.exists() {
             eprintln!("File {:?} does not exist", file);
             std::process::exit(1);
         }
     }
+}
+
+/// Queue a file, parsing source or reading bytecode from `.clacb` files
+fn load(state: &mut clacjit::State, file: &Path) {
+    if file.extension().is_some_and(|ext| ext == "clacb") {
+        let bytes = std::fs::read(file).unwrap();
+        if let Err(e) = state.load_bytecode(&bytes) {
+            eprintln!("Can not load {:?}: {}", file, e);
+            std::process::exit(1);
+        }
+    } else {
+        let input = std::fs::read_to_string(file).unwrap();
+        state.parse(&input);
+    }
+}
+
+fn build(args: Build) {
+    check_files(&args.files);
 
     let mut state = clacjit::State::new();
+    for file in &args.files {
+        load(&mut state, file);
+    }
 
+    let queue = std::mem::take(&mut state.queue).unwrap();
+    if let Err(e) = clacjit::aot::build(queue, &args.output) {
+        eprintln!("Build failed: {}", e);
+        std::process::exit(1);
+    }
+    println!("Built {:?}", args.output);
+}
+
+fn compile_bytecode(args: CompileBytecode) {
+    check_files(&args.files);
+
+    let mut state = clacjit::State::new();
     for file in &args.files {
-        let input = std::fs::read_to_string(file).unwrap();
-        print!("Parsing file {:?}... ", file);
-        state.parse(&input);
-        println!("done");
+        load(&mut state, file);
+    }
+
+    let queue = std::mem::take(&mut state.queue).unwrap();
+    let bytes = match clacjit::clacb::write(&queue) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Compile failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = std::fs::write(&args.output, bytes) {
+        eprintln!("Can not write {:?}: {}", args.output, e);
+        std::process::exit(1);
+    }
+    println!("Compiled {:?}", args.output);
+}
+
+/// Whether an evaluation is running. Ctrl-C stops it if so, and exits if not.
+static EVALUATING: AtomicBool = AtomicBool::new(false);
+
+/// Evaluate the queue, and exit on `quit`. Errors exit too, unless they
+/// happen in the REPL or come from Ctrl-C.
+fn eval(state: &mut clacjit::State, repl: bool) {
+    // Ctrl-C just as the last evaluation ended should not stop this one
+    state.interrupt_handle().store(false, Ordering::Relaxed);
+    EVALUATING.store(true, Ordering::Relaxed);
+    let result = clacjit::eval(state);
+    EVALUATING.store(false, Ordering::Relaxed);
+    match result {
+        Ok(clacjit::Outcome::Done) => {}
+        Ok(clacjit::Outcome::Quit) => std::process::exit(0),
+        Err(e) => {
+            eprintln!("{}", e);
+            if !repl && !matches!(e, clacjit::ClacError::Interrupted(_)) {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn save(state: &clacjit::State, path: &Option<PathBuf>) {
+    if let Some(path) = path {
+        if let Err(e) = state.save_snapshot(path, false) {
+            eprintln!("Can not save {:?}: {}", path, e);
+        }
+    }
+}
+
+/// The backend the arguments ask for
+fn backend(args: &Args) -> Box<dyn clacjit::ExecutionBackend> {
+    if args.bytecode {
+        return Box::new(clacjit::bytecode::BytecodeBackend::new());
+    }
+    if !args.jit {
+        return Box::<clacjit::InterpreterBackend>::default();
+    }
+
+    #[cfg(feature = "jit")]
+    {
+        let mut backend = clacjit::jit::JitBackend::new();
+        backend.set_checks(args.jit_checks);
+        if let Some(dir) = &args.cache {
+            match clacjit::jit::cache::Cache::new(dir) {
+                Ok(cache) => backend.set_cache(cache),
+                Err(e) => eprintln!("Can not use cache {:?}: {}", dir, e),
+            }
+        }
+        Box::new(backend)
+    }
+    #[cfg(not(feature = "jit"))]
+    {
+        eprintln!("JIT is not available in this build");
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    let args: Args = argh::from_env();
+    log::set_logger(&CHATTER).unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+    match args.command {
+        Some(Command::Build(build_args)) => return build(build_args),
+        Some(Command::CompileBytecode(compile_args)) => return compile_bytecode(compile_args),
+        None => {}
     }
 
-    // Eval
-    print!("Evaluating...");
+    if args.jit && args.bytecode {
+        eprintln!("Choose one of --jit and --bytecode");
+        std::process::exit(1);
+    }
+    let backend = backend(&args);
+    if args.jit {
+        eprintln!("=== JIT enabled ===");
+    }
+
+    // Check if files are accessible
+    check_files(&args.files);
+
+    let mut state = clacjit::State::with_backend(backend);
+    if args.profile {
+        state.enable_profiling();
+    }
+    state.set_max_stack(args.max_stack);
+    state.set_max_return_stack(args.max_return_stack);
+    let interrupt = state.interrupt_handle();
+    let handler = move || {
+        if !EVALUATING.load(Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+        interrupt.store(true, Ordering::Relaxed);
+    };
+    if let Err(e) = ctrlc::set_handler(handler) {
+        eprintln!("Can not handle Ctrl-C: {}", e);
+    }
+
+    if let Some(path) = &args.restore {
+        if let Err(e) = state.load_snapshot(path) {
+            eprintln!("Can not restore {:?}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+
+    for file in &args.files {
+        eprint!("Parsing file {:?}... ", file);
+        load(&mut state, file);
+        eprintln!("done");
+    }
+
+    // Eval. Only what the program prints goes to stdout, the rest to stderr.
+    eprint!("Evaluating...");
     let t0 = std::time::Instant::now();
+    state.set_fuel(args.max_steps);
+    eval(&mut state, false);
     std::io::stdout().flush().unwrap();
-    clacjit::eval(&mut state, args.jit);
-    println!("Done in {:?}", t0.elapsed());
+    eprintln!("Done in {:?}", t0.elapsed());
+    save(&state, &args.save);
 
     // Simple REPL
-    println!("Starting REPL");
+    eprintln!("Starting REPL");
     loop {
-        print!("> ");
         std::io::stdout().flush().unwrap();
+        eprint!("> ");
         let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
+        if std::io::stdin().read_line(&mut input).unwrap() == 0 {
+            save(&state, &args.save);
+            break;
+        }
         state.parse(&input);
-        clacjit::eval(&mut state, args.jit);
+        state.set_fuel(args.max_steps);
+        eval(&mut state, true);
     }
 }
@@ -0,0 +1,88 @@
+//! Words implemented by the host, in Rust.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Mutex;
+
+use crate::{ClacError, TheStack};
+
+/// Values a word pops, and then pushes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StackEffect {
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+impl StackEffect {
+    pub fn new(inputs: usize, outputs: usize) -> Self {
+        Self { inputs, outputs }
+    }
+}
+
+/// Gets the inputs, bottom first, and returns the outputs, bottom first
+pub type NativeFn = dyn FnMut(&[i32]) -> Result<Vec<i32>, ClacError> + Send;
+
+pub struct Native {
+    name: String,
+    effect: StackEffect,
+    f: Mutex<Box<NativeFn>>,
+}
+
+impl Native {
+    pub fn new(name: &str, effect: StackEffect, f: Box<NativeFn>) -> Self {
+        Self {
+            name: name.to_string(),
+            effect,
+            f: Mutex::new(f),
+        }
+    }
+
+    pub fn effect(&self) -> StackEffect {
+        self.effect
+    }
+
+    /// Run the closure, and check it kept to the stack effect. A panic is
+    /// reported as an error, since it can not unwind through jitted code.
+    pub fn apply(&self, inputs: &[i32]) -> Result<Vec<i32>, ClacError> {
+        let mut f = self.f.lock().unwrap();
+        let outputs = match catch_unwind(AssertUnwindSafe(|| f(inputs))) {
+            Ok(outputs) => outputs?,
+            Err(panic) => {
+                let message = match panic.downcast_ref::<&str>() {
+                    Some(message) => message.to_string(),
+                    None => match panic.downcast_ref::<String>() {
+                        Some(message) => message.clone(),
+                        None => "unknown".to_string(),
+                    },
+                };
+                return Err(ClacError::Native(format!(
+                    "{} panicked: {}",
+                    self.name, message
+                )));
+            }
+        };
+        if outputs.len() != self.effect.outputs {
+            return Err(ClacError::Native(format!(
+                "{} returned {} values instead of {}",
+                self.name,
+                outputs.len(),
+                self.effect.outputs
+            )));
+        }
+        Ok(outputs)
+    }
+
+    /// Pop the inputs from the stack, and push the outputs
+    pub fn call(&self, stack: &mut TheStack) -> Result<(), ClacError> {
+        if stack.len() < self.effect.inputs {
+            return Err(ClacError::StackUnderflow);
+        }
+        let mut inputs: Vec<i32> = (0..self.effect.inputs)
+            .map(|_| stack.pop().unwrap())
+            .collect();
+        inputs.reverse();
+        for value in self.apply(&inputs)? {
+            stack.push(value);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+//! Peephole pass fusing common stack idioms into superinstructions.
+//!
+//! `skip` and `if` count tokens, so fusing must not move anything: the
+//! superinstruction replaces the first token of its pattern, and the tokens it
+//! covers stay in place, to be stepped over by whoever executes it (see
+//! `Token::width`). A pattern is only fused when no jump can land inside it,
+//! and nothing is fused in queues with dynamic skips.
+
+use std::collections::HashSet;
+
+use crate::{Queue, Token};
+
+/// Positions execution can jump to, or `None` if a skip is not constant
+fn jump_targets(tokens: &[Token]) -> Option<HashSet<usize>> {
+    use Token::*;
+
+    let mut targets = HashSet::new();
+    let mut skips = vec![];
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            If => {
+                targets.insert(i + 4);
+            }
+            Skip => match i.checked_sub(1).map(|j| &tokens[j]) {
+                Some(Num(n)) if *n >= 0 => {
+                    targets.insert(i + 1 + *n as usize);
+                    skips.push(i);
+                }
+                _ => return None,
+            },
+            _ => {}
+        }
+    }
+
+    // Landing right on a skip makes its count dynamic
+    if skips.iter().any(|i| targets.contains(i)) {
+        return None;
+    }
+    Some(targets)
+}
+
+/// Match a pattern at the start of `tokens`
+fn fuse(tokens: &[Token]) -> Option<Token> {
+    use Token::*;
+
+    match tokens {
+        [Num(a), Pick, Num(b), Pick, Less, ..] if *a > 0 && *b > 0 => Some(PickPickLess(*a, *b)),
+        [If, Num(n), Skip, _, ..] if *n > 0 => Some(IfSkip(*n)),
+        [Num(1), Pick, ..] => Some(Dup),
+        [Num(2), Pick, ..] => Some(Over),
+        [Swap, Drop, ..] => Some(Nip),
+        [Rot, Rot, ..] => Some(RotRot),
+        _ => None,
+    }
+}
+
+pub fn optimize(queue: Queue<Token>) -> Queue<Token> {
+    let mut tokens: Vec<Token> = queue.iter().cloned().collect();
+    let Some(targets) = jump_targets(&tokens) else {
+        return queue;
+    };
+
+    let mut i = 0;
+    while i < tokens.len() {
+        if let Some(token) = fuse(&tokens[i..]) {
+            let width = token.width();
+            let lands_inside = (i + 1..i + width).any(|j| targets.contains(&j));
+            let jumps_out = match token {
+                Token::IfSkip(n) => i + 3 + n as usize > tokens.len(),
+                _ => false,
+            };
+            if !lands_inside && !jumps_out {
+                tokens[i] = token;
+                i += width;
+                continue;
+            }
+        }
+        i += 1;
+    }
+
+    let mut queue = Queue::new();
+    for token in tokens {
+        queue.push(token);
+    }
+    queue
+}
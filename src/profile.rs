@@ -0,0 +1,117 @@
+//! Execution profiles, gathered by the interpreter and by instrumented
+//! jitted code, and used to recompile hot definitions.
+//!
+//! Positions are slots in the optimized body of a definition, as compiled.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Fewest observations a decision is based on
+const MIN_SAMPLES: u64 = 100;
+
+/// Share of observations, in percent, a case needs to be dominant
+const DOMINANT: u64 = 90;
+
+#[derive(Default)]
+pub struct Profile {
+    // Shared with the jitted code gathering them, which may outlive this
+    words: HashMap<String, Arc<Mutex<WordProfile>>>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What was gathered for a word so far
+    pub fn word(&self, name: &str) -> Option<WordProfile> {
+        self.words
+            .get(name)
+            .map(|word| word.lock().unwrap().clone())
+    }
+
+    pub fn word_mut(&mut self, name: &str) -> MutexGuard<'_, WordProfile> {
+        self.words
+            .entry(name.to_string())
+            .or_default()
+            .lock()
+            .unwrap()
+    }
+
+    /// Forget what was gathered for a redefined word, and start over. Code
+    /// still holding the old profile keeps gathering into it.
+    pub fn reset(&mut self, name: &str) -> Arc<Mutex<WordProfile>> {
+        let word = Arc::new(Mutex::new(WordProfile::default()));
+        self.words.insert(name.to_string(), word.clone());
+        word
+    }
+
+    /// Whether `word` is the profile of the current definition of `name`
+    pub fn is_current(&self, name: &str, word: &Arc<Mutex<WordProfile>>) -> bool {
+        self.words
+            .get(name)
+            .is_some_and(|current| Arc::ptr_eq(current, word))
+    }
+
+    pub fn words(&self) -> impl Iterator<Item = (&str, WordProfile)> {
+        self.words
+            .iter()
+            .map(|(name, word)| (name.as_str(), word.lock().unwrap().clone()))
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct WordProfile {
+    pub calls: u64,
+    /// Zero and non-zero conditions seen by each `if`
+    pub branches: HashMap<usize, [u64; 2]>,
+    /// Values seen by each `skip` without a constant count
+    pub skips: HashMap<usize, HashMap<i32, u64>>,
+    /// What the code recompiled for this profile was specialized on, once
+    /// the definition got hot
+    pub specialized: Option<Specialized>,
+}
+
+/// Decisions taken from a profile when recompiling a definition
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Specialized {
+    /// Ranges of tokens rarely run, moved out of the hot path
+    pub cold: Vec<Range<usize>>,
+    /// Skips without a constant count, with the count tried first
+    pub skips: Vec<(usize, i32)>,
+}
+
+impl WordProfile {
+    pub fn record_if(&mut self, pos: usize, cond: i32) {
+        self.branches.entry(pos).or_default()[(cond != 0) as usize] += 1;
+    }
+
+    pub fn record_skip(&mut self, pos: usize, n: i32) {
+        *self.skips.entry(pos).or_default().entry(n).or_default() += 1;
+    }
+
+    /// Whether the `if` at `pos` almost always sees zero (`Some(true)`) or
+    /// almost always sees non-zero (`Some(false)`)
+    pub fn branch_bias(&self, pos: usize) -> Option<bool> {
+        let [zero, non_zero] = *self.branches.get(&pos)?;
+        let total = zero + non_zero;
+        if total < MIN_SAMPLES {
+            None
+        } else if zero * 100 >= total * DOMINANT {
+            Some(true)
+        } else if non_zero * 100 >= total * DOMINANT {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// The value the `skip` at `pos` almost always sees
+    pub fn dominant_skip(&self, pos: usize) -> Option<i32> {
+        let values = self.skips.get(&pos)?;
+        let total: u64 = values.values().sum();
+        let (n, count) = values.iter().max_by_key(|(_, count)| **count)?;
+        (total >= MIN_SAMPLES && count * 100 >= total * DOMINANT).then_some(*n)
+    }
+}
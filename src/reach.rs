@@ -0,0 +1,108 @@
+//! Reachability pass dropping dead code from definitions.
+//!
+//! Code after `quit`, and ranges always jumped over by a constant `n skip`,
+//! are never run. They are removed, and the skips over them are shortened.
+//! The three tokens after an `if` are kept even if dead, because a false
+//! condition always skips exactly three tokens.
+
+use crate::{Queue, Token};
+
+/// Tokens to keep, or `None` if a skip is not constant
+fn live(tokens: &[Token]) -> Option<Vec<bool>> {
+    use Token::*;
+
+    let len = tokens.len();
+    let skip_count = |i: usize| match i.checked_sub(1).map(|j| &tokens[j]) {
+        Some(Num(n)) if *n >= 0 => Some(*n as usize),
+        _ => None,
+    };
+
+    // Landing right on a skip makes its count dynamic
+    for (i, token) in tokens.iter().enumerate() {
+        let target = match token {
+            If => i + 4,
+            Skip => i + 1 + skip_count(i)?,
+            DefBegin | DefEnd => return None,
+            _ => continue,
+        };
+        if tokens.get(target) == Some(&Skip) {
+            return None;
+        }
+    }
+
+    let mut keep = vec![false; len];
+    let mut work = vec![0];
+    while let Some(i) = work.pop() {
+        if i >= len || keep[i] {
+            continue;
+        }
+        keep[i] = true;
+        match &tokens[i] {
+            If => work.extend([i + 1, i + 4]),
+            Skip => work.push(i + 1 + skip_count(i)?),
+            Quit => {}
+            _ => work.push(i + 1),
+        }
+    }
+
+    for i in 0..len {
+        if keep[i] && tokens[i] == If {
+            for k in &mut keep[(i + 1).min(len)..(i + 4).min(len)] {
+                *k = true;
+            }
+        }
+    }
+    // A kept skip keeps its count
+    for i in 1..len {
+        if keep[i] && tokens[i] == Skip {
+            keep[i - 1] = true;
+        }
+    }
+    Some(keep)
+}
+
+/// Drop unreachable tokens from the definition `name`, with a warning
+pub fn prune(queue: Queue<Token>, name: &str) -> Queue<Token> {
+    let tokens: Vec<Token> = queue.iter().cloned().collect();
+    let Some(keep) = live(&tokens) else {
+        return queue;
+    };
+    let dropped = keep.iter().filter(|k| !**k).count();
+    if dropped == 0 {
+        return queue;
+    }
+    log::warn!(
+        "{} unreachable token{} in {}",
+        dropped,
+        if dropped == 1 { "" } else { "s" },
+        name
+    );
+
+    // New position of each token, or of the next kept one
+    let len = tokens.len();
+    let mut pos = Vec::with_capacity(len + 1);
+    let mut n = 0;
+    for k in &keep {
+        pos.push(n);
+        if *k {
+            n += 1;
+        }
+    }
+    pos.push(n);
+    let new_pos = |i: usize| if i <= len { pos[i] } else { n + i - len };
+
+    let mut pruned = Queue::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if !keep[i] {
+            continue;
+        }
+        match (token, tokens.get(i + 1)) {
+            (Token::Num(skip), Some(Token::Skip)) if keep[i + 1] => {
+                let target = new_pos(i + 2 + *skip as usize);
+                pruned.push(Token::Num((target - pos[i + 1] - 1) as i32));
+            }
+            _ => pruned.push(token.clone()),
+        }
+    }
+    pruned
+}
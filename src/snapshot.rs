@@ -0,0 +1,185 @@
+//! Saving a `State` to a file, and loading it back.
+//!
+//! Snapshots are text, one item per line, with tokens as they were written
+//! in the source:
+//!
+//! ```text
+//! clacjit snapshot 1
+//! def sq 1 pick *
+//! stack 3 4
+//! queue sq print
+//! call <word> <tokens left in its caller>
+//! ```
+//!
+//! `queue` and `call` lines are only written for programs paused part way,
+//! with `State::step`. Natives are not saved, so the host has to register
+//! them again.
+
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use crate::{parse, peephole, reach, ClacError, Queue, ReturnStack, State, TheQueue, Token};
+
+const HEADER: &str = "clacjit snapshot 1";
+
+fn invalid(line: usize, message: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid snapshot, line {}: {}", line, message),
+    )
+}
+
+fn write_tokens<'a>(
+    out: &mut impl Write,
+    head: &str,
+    tokens: impl Iterator<Item = &'a Token>,
+) -> std::io::Result<()> {
+    write!(out, "{}", head)?;
+    for token in tokens {
+        write!(out, " {}", token)?;
+    }
+    writeln!(out)
+}
+
+/// Everything read from a snapshot, before any of it is applied
+#[derive(Default)]
+struct Snapshot {
+    defs: Vec<(String, Queue<Token>)>,
+    stack: Vec<i32>,
+    queue: Option<Queue<Token>>,
+    calls: Vec<(String, Queue<Token>)>,
+}
+
+impl Snapshot {
+    fn read(input: impl BufRead) -> std::io::Result<Snapshot> {
+        let mut snapshot = Snapshot::default();
+        let mut lines = input.lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(invalid(1, "not a snapshot, or from another version"));
+        }
+
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            let number = i + 2;
+            let (head, rest) = line.split_once(' ').unwrap_or((&line, ""));
+            match head {
+                "def" | "call" => {
+                    let (name, body) = rest.split_once(' ').unwrap_or((rest, ""));
+                    if name.is_empty() || !matches!(parse(name).pop(), Some(Token::Custom(_))) {
+                        return Err(invalid(number, format!("bad word name {:?}", name)));
+                    }
+                    let body = parse(body).unwrap();
+                    if body
+                        .iter()
+                        .any(|t| matches!(t, Token::DefBegin | Token::DefEnd))
+                    {
+                        return Err(invalid(number, "definition tokens in a body"));
+                    }
+                    let list = match head {
+                        "def" => &mut snapshot.defs,
+                        _ => &mut snapshot.calls,
+                    };
+                    list.push((name.to_string(), body));
+                }
+                "stack" => {
+                    for value in rest.split_whitespace() {
+                        let value = value
+                            .parse()
+                            .map_err(|_| invalid(number, format!("bad value {:?}", value)))?;
+                        snapshot.stack.push(value);
+                    }
+                }
+                "queue" => snapshot.queue = Some(parse(rest).unwrap()),
+                _ => return Err(invalid(number, format!("unknown item {:?}", head))),
+            }
+        }
+        if snapshot.queue.is_none() && !snapshot.calls.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid snapshot: calls without a queue",
+            ));
+        }
+        Ok(snapshot)
+    }
+}
+
+impl State {
+    /// Save the definitions and the data stack to a file. With `pending`,
+    /// the queue and the return stack are saved too, so that a program
+    /// paused with `step` can be resumed.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>, pending: bool) -> std::io::Result<()> {
+        let backend = self
+            .backend
+            .as_deref()
+            .expect("can not save while evaluating");
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+
+        writeln!(out, "{}", HEADER)?;
+        for name in self.definitions() {
+            if let Some(body) = backend.body(self, &name) {
+                write_tokens(&mut out, &format!("def {}", name), body.iter())?;
+            }
+        }
+        write!(out, "stack")?;
+        for value in self.stack.iter() {
+            write!(out, " {}", value)?;
+        }
+        writeln!(out)?;
+
+        if pending {
+            write_tokens(&mut out, "queue", self.queue.iter())?;
+            // Each word called, with what is left of its caller
+            for (name, caller) in self.frames.iter().zip(self.return_stack.iter()) {
+                write_tokens(&mut out, &format!("call {}", name), caller.iter())?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Load a snapshot saved with `save_snapshot`. Its definitions are bound
+    /// again, and so compiled again by the backend, and its stack replaces
+    /// the data stack. A snapshot with a pending program replaces the queue
+    /// and the return stack too. Malformed files, and snapshots over the
+    /// limits set with `set_max_stack` and `set_max_return_stack`, change
+    /// nothing.
+    pub fn load_snapshot(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let file = std::fs::File::open(path)?;
+        let snapshot = Snapshot::read(BufReader::new(file))?;
+        if snapshot.stack.len() > self.max_stack {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                ClacError::StackOverflow,
+            ));
+        }
+        if snapshot.calls.len() > self.max_return_stack {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                ClacError::ReturnStackOverflow,
+            ));
+        }
+
+        let mut backend = self.backend.take().expect("can not load while evaluating");
+        for (name, def) in snapshot.defs {
+            let def = peephole::optimize(reach::prune(def, &name));
+            backend.define(self, &name, def);
+        }
+        self.backend = Some(backend);
+
+        self.stack.clear();
+        for value in snapshot.stack {
+            self.stack.push(value);
+        }
+
+        if let Some(queue) = snapshot.queue {
+            self.queue = TheQueue::Real(queue);
+            self.return_stack = ReturnStack::new();
+            self.frames.clear();
+            self.library_frames.clear();
+            for (name, caller) in snapshot.calls {
+                self.return_stack.push(TheQueue::Real(caller));
+                self.frames.push(name);
+            }
+        }
+        Ok(())
+    }
+}
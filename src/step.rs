@@ -0,0 +1,113 @@
+//! Running a program one token at a time.
+
+use crate::{exec, ClacError, Event, State, Token};
+
+/// What a single step did
+#[derive(Clone, Debug, PartialEq)]
+pub enum Step {
+    /// Ran a token, a superinstruction, or a whole word the backend does not
+    /// step into. Values are listed bottom first.
+    Ran {
+        token: Token,
+        popped: Vec<i32>,
+        pushed: Vec<i32>,
+    },
+    /// Ran `if`, `skip`, or a superinstruction ending in one, and jumped
+    /// over `count` tokens
+    Skipped {
+        token: Token,
+        popped: Vec<i32>,
+        count: usize,
+    },
+    /// Entered a word, whose tokens are run by the next steps
+    Called(String),
+    /// Got to the end of a word, and went back to its caller
+    Returned,
+    Defined(String),
+    /// Ran `quit`. What was left to run is dropped.
+    Quit,
+    /// Nothing is left to run
+    Done,
+}
+
+/// Values a token pops before it pushes anything, if known
+fn pops(token: &Token) -> Option<usize> {
+    use Token::*;
+    match token {
+        Num(_) | Dup | Over | PickPickLess(..) | Quit | DefBegin | DefEnd => Some(0),
+        Pick | Drop | Print | If | Skip | IfSkip(_) => Some(1),
+        Add | Sub | Mul | Div | Mod | Pow | Less | Swap | Nip => Some(2),
+        Rot | RotRot => Some(3),
+        Custom(_) => None,
+    }
+}
+
+impl State {
+    /// Run exactly one token, or one superinstruction, and report what it
+    /// did. Words are stepped into by the interpreter backend, while other
+    /// backends and natives run them whole. Errors drop the rest of the
+    /// queue, like `eval`.
+    pub fn step(&mut self) -> Result<Step, ClacError> {
+        if self.is_end() {
+            return Ok(Step::Done);
+        }
+        if self.queue.is_empty() {
+            self.after_return();
+            return Ok(Step::Returned);
+        }
+
+        let token = self.queue.pop().unwrap();
+        let before = match pops(&token) {
+            Some(n) => n.min(self.stack.len()),
+            None => self.stack.len(),
+        };
+        let base = self.stack.len() - before;
+        let popped = self.stack.as_slice()[base..].to_vec();
+        let depth = self.return_stack.len();
+
+        let mut backend = self.backend.take().expect("step is not reentrant");
+        let result = exec(self, backend.as_mut(), token.clone());
+        self.backend = Some(backend);
+
+        let event = result.inspect_err(|_| self.abort())?;
+
+        let after = self.stack.as_slice();
+        let kept = match pops(&token) {
+            Some(_) => 0,
+            // Leave out the bottom values a word kept as they were
+            None => popped
+                .iter()
+                .zip(&after[base.min(after.len())..])
+                .take_while(|(a, b)| a == b)
+                .count(),
+        };
+        let popped = popped[kept..].to_vec();
+        let pushed = after[(base + kept).min(after.len())..].to_vec();
+
+        Ok(match event {
+            Event::Ran => Step::Ran {
+                token,
+                popped,
+                pushed,
+            },
+            Event::Skipped(count) => Step::Skipped {
+                token,
+                popped,
+                count,
+            },
+            Event::Called => match token {
+                Token::Custom(name) if self.return_stack.len() > depth => Step::Called(name),
+                token => Step::Ran {
+                    token,
+                    popped,
+                    pushed,
+                },
+            },
+            Event::Defined(name) => Step::Defined(name),
+            Event::Quit => {
+                self.abort();
+                Step::Quit
+            }
+        })
+    }
+}
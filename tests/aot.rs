@@ -0,0 +1,142 @@
+//! Programs built ahead of time into executables, and run.
+
+use std::io::Write;
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+
+use clacjit::{parse, ClacError, State};
+
+/// What the interpreter prints, shared with the test
+#[derive(Clone, Default)]
+struct Printed(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Printed {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Build `source` into an executable named `name`, and run it
+fn build_and_run(name: &str, source: &str) -> Output {
+    // Next to the test binary, in target/<profile>/deps
+    let program = std::env::current_exe().unwrap().with_file_name(name);
+    clacjit::aot::build(parse(source).unwrap(), &program)
+        .expect("as and ld from binutils are needed to build executables");
+    Command::new(&program).output().unwrap()
+}
+
+#[test]
+fn program_prints_and_quits() {
+    let output = build_and_run(
+        "aot-tri",
+        ": nop ; : rec 1 pick 1 - tri + ; : tri 1 pick if rec 1 skip nop ; \
+         10 tri print -7 print quit 1 print",
+    );
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "55\n-7\n");
+}
+
+#[test]
+fn runtime_errors_are_reported() {
+    let cases = [
+        ("aot-div-zero", "1 0 /", "Division by zero"),
+        ("aot-mod-zero", "1 0 %", "Division by zero"),
+        ("aot-div-overflow", "-2147483648 -1 /", "Overflow"),
+        ("aot-mod-overflow", "-2147483648 -1 %", "Overflow"),
+        ("aot-pow", "2 -1 **", "Negative exponent"),
+        ("aot-underflow", "1 +", "Stack underflow"),
+    ];
+    for (name, source, message) in cases {
+        let output = build_and_run(name, source);
+        assert_eq!(output.status.code(), Some(1), "{}", source);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            format!("{}\n", message),
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn skips_into_definitions() {
+    // The rest of the definition runs as top-level code, up to its `;`
+    let cases = [
+        ("aot-skip-name", "1 skip : f 7 print ; 2 print"),
+        ("aot-skip-body", "2 skip : f 7 print ; 2 print"),
+        ("aot-skip-end", "4 skip : f 7 print ; 2 print"),
+        ("aot-skip-quit", "2 skip : f quit ; 2 print"),
+        ("aot-skip-over", "5 skip : f 7 print ; 2 print"),
+    ];
+    for (name, source) in cases {
+        let mut state = State::new();
+        let printed = Printed::default();
+        state.set_output(Box::new(printed.clone()));
+        let result = state.run_str(source).map(|_| ());
+
+        let output = build_and_run(name, source);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8(printed.0.lock().unwrap().clone()).unwrap(),
+            "{}",
+            source
+        );
+        match result {
+            Ok(()) => assert!(output.status.success(), "{}", source),
+            Err(error) => {
+                assert_eq!(output.status.code(), Some(1), "{}", source);
+                assert_eq!(
+                    String::from_utf8_lossy(&output.stderr),
+                    format!("{}\n", error),
+                    "{}",
+                    source
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn builds_run_at_once() {
+    let workers: Vec<_> = (0..8)
+        .map(|i| {
+            std::thread::spawn(move || {
+                let output = build_and_run(&format!("aot-parallel-{}", i), &format!("{} print", i));
+                assert!(output.status.success());
+                assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{}\n", i));
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}
+
+#[test]
+fn malformed_programs_are_errors() {
+    let cases = [
+        (": f 1", ClacError::QueueUnderflow),
+        (": ;", ClacError::EmptyDefinition),
+        (": 1 2 ;", ClacError::InvalidDefinition),
+        (": f : g ; ;", ClacError::InvalidDefinition),
+        ("1 ;", ClacError::UnexpectedDefinitionEnd),
+    ];
+    for (source, error) in cases {
+        assert_eq!(
+            clacjit::aot::compile(parse(source).unwrap()),
+            Err(error),
+            "{}",
+            source
+        );
+    }
+
+    let fused = clacjit::peephole::optimize(parse("1 1 pick").unwrap());
+    assert!(matches!(
+        clacjit::aot::compile(fused),
+        Err(ClacError::Unsupported(_))
+    ));
+}
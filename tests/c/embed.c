@@ -0,0 +1,76 @@
+/* Runs clac programs through the C API, and exits with 1 on a mismatch */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "clacjit.h"
+
+static int failures = 0;
+
+#define CHECK(cond)                                                    \
+    do {                                                               \
+        if (!(cond)) {                                                 \
+            fprintf(stderr, "%s:%d: %s\n", __FILE__, __LINE__, #cond); \
+            failures++;                                                \
+        }                                                              \
+    } while (0)
+
+static int run(ClacState *state, const char *source) {
+    CHECK(clac_load(state, source) == CLAC_DONE);
+    return clac_run(state);
+}
+
+static void check_backend(int backend) {
+    ClacState *state = clac_state_new(backend);
+    if (!state) {
+        /* The JIT is left out of some builds */
+        CHECK(backend == CLAC_JIT);
+        return;
+    }
+
+    CHECK(run(state, ": sq 1 pick * ; 7 sq") == CLAC_DONE);
+    CHECK(clac_last_error(state) == NULL);
+    CHECK(clac_stack_len(state) == 1);
+    CHECK(clac_stack(state)[0] == 49);
+
+    /* Inputs pushed from C */
+    clac_clear_stack(state);
+    clac_push(state, 3);
+    clac_push(state, 4);
+    CHECK(run(state, "sq swap sq +") == CLAC_DONE);
+    CHECK(clac_stack_len(state) == 1);
+    CHECK(clac_stack(state)[0] == 25);
+
+    /* Errors keep the stack, and the definitions */
+    CHECK(run(state, "1 0 /") == CLAC_ERROR);
+    CHECK(strcmp(clac_last_error(state), "Division by zero") == 0);
+    CHECK(run(state, "nope") == CLAC_ERROR);
+    CHECK(strstr(clac_last_error(state), "nope") != NULL);
+    CHECK(clac_stack_len(state) == 1);
+    CHECK(run(state, "2 sq") == CLAC_DONE);
+    CHECK(clac_last_error(state) == NULL);
+    CHECK(clac_stack(state)[1] == 4);
+
+    CHECK(run(state, "5 quit 6") == CLAC_QUIT);
+    CHECK(clac_stack_len(state) == 3);
+
+    CHECK(clac_load(state, "\xff") == CLAC_ERROR);
+    CHECK(clac_last_error(state) != NULL);
+
+    clac_state_free(state);
+}
+
+int main(void) {
+    check_backend(CLAC_INTERPRETER);
+    check_backend(CLAC_BYTECODE);
+    check_backend(CLAC_JIT);
+
+    CHECK(clac_state_new(42) == NULL);
+    clac_state_free(NULL);
+
+    if (failures) {
+        return 1;
+    }
+    printf("ok\n");
+    return 0;
+}
@@ -0,0 +1,84 @@
+//! Jitted code at each checks level, against the interpreter.
+
+#![cfg(feature = "jit")]
+
+use clacjit::jit::{Checks, JitBackend};
+use clacjit::{ClacError, State};
+
+/// Programs every level runs the same as the interpreter
+const CORRECT: &[&str] = &[
+    // Self-calls that are not in tail position
+    ": c 1 pick if 1 - c 1 + ; 3 c",
+    // Tail calls, directly and through constant skips
+    ": down 1 pick if 1 - down ; 1000 down",
+    ": down2 1 pick if 3 skip 0 3 skip 1 - down2 0 skip ; 1000 down2",
+    // A self-call jumped over
+    ": f 1 pick 2 skip f f 10 + ; 1 f",
+    "1 2 3 swap rot 3 rot 4 pick 2 pick < 7 2 % 9 4 / 2 10 ** -5 3 %",
+];
+
+/// Broken programs, and the levels that have to report them
+const BROKEN: &[(&str, Checks)] = &[
+    ("1 +", Checks::Underflow),
+    ("drop", Checks::Underflow),
+    ("1 2 3 pick", Checks::Underflow),
+    ("1 0 pick", Checks::Underflow),
+    ("1 -1 pick", Checks::Underflow),
+    (": f 1 pick 0 pick ; 1 f", Checks::Underflow),
+    (": f 5 skip ; f", Checks::Underflow),
+    (": f -1 skip ; f", Checks::Underflow),
+    ("1 0 /", Checks::Full),
+    ("1 0 %", Checks::Full),
+    ("-2147483648 -1 /", Checks::Full),
+    ("2 -1 **", Checks::Full),
+];
+
+fn run(source: &str, checks: Option<Checks>) -> (Vec<i32>, Result<(), ClacError>) {
+    let mut state = match checks {
+        None => State::new(),
+        Some(checks) => {
+            let mut backend = JitBackend::new();
+            backend.set_checks(checks);
+            State::with_backend(Box::new(backend))
+        }
+    };
+    let result = state.run_str(source).map(|_| ());
+    (state.stack().to_vec(), result)
+}
+
+#[test]
+fn correct_programs_agree_at_every_level() {
+    for source in CORRECT {
+        let expected = run(source, None);
+        assert_eq!(expected.1, Ok(()), "{}", source);
+        for checks in [Checks::None, Checks::Underflow, Checks::Full] {
+            assert_eq!(
+                run(source, Some(checks)),
+                expected,
+                "{} with {}",
+                source,
+                checks
+            );
+        }
+    }
+}
+
+#[test]
+fn broken_programs_fail_the_same() {
+    for (source, level) in BROKEN {
+        let expected = run(source, None);
+        assert!(expected.1.is_err(), "{}", source);
+        for checks in [Checks::Underflow, Checks::Full] {
+            if checks == Checks::Underflow && *level == Checks::Full {
+                continue;
+            }
+            assert_eq!(
+                run(source, Some(checks)),
+                expected,
+                "{} with {}",
+                source,
+                checks
+            );
+        }
+    }
+}
@@ -0,0 +1,32 @@
+//! Top-level code compiled by the JIT, and freed once it ran.
+//!
+//! Alone in its file, as the count of linked units is shared by every test
+//! running in the process.
+
+#![cfg(feature = "jit")]
+
+use clacjit::jit::{linked_units, JitBackend};
+use clacjit::{ClacError, Outcome, State};
+
+#[test]
+fn chunks_are_not_retained() {
+    let mut state = State::with_backend(Box::new(JitBackend::new()));
+    state.run_str(": sq 1 pick * ;").unwrap();
+    let before = linked_units();
+
+    for i in 0..5000 {
+        assert_eq!(state.run_str("3 sq 2 +").unwrap(), [11]);
+        state.clear_stack();
+        // Chunks that stop part way are freed too
+        assert_eq!(state.run_str("1 drop drop"), Err(ClacError::StackUnderflow));
+        assert_eq!(state.run_str_outcome("4 quit").unwrap().0, Outcome::Quit);
+        state.clear_stack();
+        assert!(linked_units() <= before, "after {} runs", i);
+    }
+    assert_eq!(linked_units(), before);
+
+    // Definitions stay
+    state.run_str(": cube 1 pick sq * ;").unwrap();
+    assert_eq!(linked_units(), before + 1);
+    assert_eq!(state.run_str("2 cube").unwrap(), [8]);
+}
@@ -0,0 +1,116 @@
+//! Bytecode files written and read back, and malformed ones rejected.
+
+use clacjit::{clacb, parse, peephole, ClacError, ExecutionBackend, State, Token};
+
+fn backends() -> Vec<fn() -> Box<dyn ExecutionBackend>> {
+    vec![
+        || Box::<clacjit::InterpreterBackend>::default(),
+        || Box::new(clacjit::bytecode::BytecodeBackend::new()),
+        #[cfg(feature = "jit")]
+        || Box::new(clacjit::jit::JitBackend::new()),
+    ]
+}
+
+fn tokens(source: &str) -> Vec<Token> {
+    parse(source).unwrap().iter().cloned().collect()
+}
+
+fn write(source: &str) -> Result<Vec<u8>, ClacError> {
+    clacb::write(&parse(source).unwrap())
+}
+
+/// The error reading `bytes`, which must not panic
+fn read_error(bytes: &[u8]) -> String {
+    let error = match clacb::read(bytes) {
+        Ok(_) => panic!("read {:?}", bytes),
+        Err(error) => error,
+    };
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    error.to_string()
+}
+
+const PROGRAM: &str = "
+    : sq 1 pick * ;
+    : abs 1 pick 0 < if 0 swap - ;
+    -7 abs sq 2 10 ** 3 % 5 2 / 1 2 swap rot drop 4 skip 1 2 3 4 print quit
+";
+
+#[test]
+fn round_trip() {
+    for source in [PROGRAM, "", "1 2 +", ": f ;", ": f 1 ; : g f f ; g g"] {
+        let bytes = write(source).unwrap();
+        let read: Vec<Token> = clacb::read(&bytes).unwrap().iter().cloned().collect();
+        assert_eq!(read, tokens(source), "{}", source);
+    }
+}
+
+#[test]
+fn loaded_programs_run_like_source() {
+    let bytes = write(PROGRAM).unwrap();
+    for backend in backends() {
+        let mut source = State::with_backend(backend());
+        source.set_output(Box::new(std::io::sink()));
+        let expected = source
+            .run_str_outcome(PROGRAM)
+            .map(|(outcome, stack)| (outcome, stack.to_vec()));
+
+        let mut loaded = State::with_backend(backend());
+        loaded.set_output(Box::new(std::io::sink()));
+        loaded.load_bytecode(&bytes).unwrap();
+        let actual = clacjit::eval(&mut loaded).map(|outcome| (outcome, loaded.stack().to_vec()));
+        assert_eq!(actual, expected);
+    }
+}
+
+#[test]
+fn unwritable_programs() {
+    assert_eq!(write(": f 1"), Err(ClacError::QueueUnderflow));
+    assert_eq!(write("1 ;"), Err(ClacError::UnexpectedDefinitionEnd));
+    assert_eq!(write(": ;"), Err(ClacError::EmptyDefinition));
+    assert_eq!(write(": 1 2 ;"), Err(ClacError::InvalidDefinition));
+    assert_eq!(write(": f : g ; ;"), Err(ClacError::InvalidDefinition));
+    // Optimized queues hold superinstructions, which are not stored
+    let optimized = peephole::optimize(parse("1 pick swap drop").unwrap());
+    assert!(matches!(
+        clacb::write(&optimized),
+        Err(ClacError::Unsupported(_))
+    ));
+}
+
+#[test]
+fn truncated_files() {
+    let bytes = write(PROGRAM).unwrap();
+    for len in 0..bytes.len() {
+        read_error(&bytes[..len]);
+    }
+    let mut longer = bytes.clone();
+    longer.push(0);
+    assert!(read_error(&longer).contains("trailing bytes"));
+}
+
+#[test]
+fn corrupted_files() {
+    // Laid out as the module documents: header, the symbol `foo`, then the
+    // definition of `foo` and the top-level code
+    let bytes = write(": foo 2 ; 1 foo").unwrap();
+    assert_eq!(bytes.len(), 56);
+    assert_eq!(&bytes[20..23], b"foo");
+    let corrupt = |at: usize, with: &[u8]| {
+        let mut bytes = bytes.clone();
+        bytes[at..at + with.len()].copy_from_slice(with);
+        read_error(&bytes)
+    };
+
+    assert!(corrupt(0, b"CLACX").contains("not a bytecode file"));
+    assert!(corrupt(8, &[9]).contains("version 9"));
+    assert!(corrupt(20, b"f o").contains("bad word name"));
+    assert!(corrupt(20, &[0xff]).contains("Invalid bytecode file"));
+    assert!(corrupt(27, &[7]).contains("unknown item 7"));
+    // The tag of the top-level `1`
+    assert!(corrupt(46, &[99]).contains("unknown token 99"));
+    // The symbol of the top-level `foo`
+    assert!(corrupt(52, &[5]).contains("unknown symbol 5"));
+    // The count of top-level tokens, and of symbols
+    assert!(corrupt(42, &[0xff; 4]).contains("count past the end"));
+    assert!(corrupt(12, &[0xff; 4]).contains("count past the end"));
+}
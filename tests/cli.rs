@@ -0,0 +1,102 @@
+//! The command line interface, run as a separate process.
+
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStderr, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Start the binary on a file holding `file`, named after `name`
+fn spawn(name: &str, args: &[&str], file: &str) -> (Child, PathBuf) {
+    let path = std::env::temp_dir().join(format!("clacjit-{}-{}.clac", name, std::process::id()));
+    std::fs::write(&path, file).unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_clacjit"))
+        .args(args)
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    (child, path)
+}
+
+/// Run the binary on `file` with `args`, typing `input` at the REPL, and
+/// return what it wrote to stdout and to stderr
+fn run(args: &[&str], file: &str, input: &str) -> (String, String) {
+    let (mut child, path) = spawn("run", args, file);
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(path).unwrap();
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn stdout_only_holds_what_programs_print() {
+    let mut modes = vec![vec![], vec!["--bytecode"]];
+    if cfg!(feature = "jit") {
+        modes.push(vec!["--jit"]);
+    }
+    for args in modes {
+        let (stdout, stderr) = run(&args, ": sq 1 pick * ; 3 sq print", "4 sq print\n");
+        assert_eq!(stdout, "9\n16\n", "{:?}", args);
+        assert!(stderr.contains("Starting REPL"), "{:?}", args);
+    }
+}
+
+/// Read stderr until it ends with `end`, and return what was read
+fn read_until(stderr: &mut ChildStderr, end: &str) -> String {
+    let mut read = Vec::new();
+    let mut byte = [0];
+    while !read.ends_with(end.as_bytes()) {
+        assert_eq!(stderr.read(&mut byte).unwrap(), 1, "{:?}", read);
+        read.push(byte[0]);
+    }
+    String::from_utf8(read).unwrap()
+}
+
+/// Send Ctrl-C to the process
+#[cfg(unix)]
+fn interrupt(child: &Child) {
+    let status = Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[cfg(unix)]
+#[test]
+fn ctrl_c_stops_evaluations_and_exits_at_the_prompt() {
+    let (mut child, path) = spawn("ctrl-c", &[], ": forever 1 drop forever ;");
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    read_until(&mut stderr, "> ");
+
+    // A running evaluation is stopped, and the REPL goes on
+    stdin.write_all(b"forever\n").unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    interrupt(&child);
+    assert!(read_until(&mut stderr, "> ").starts_with("Interrupted"));
+
+    // At the prompt, with stdin still open, Ctrl-C exits
+    interrupt(&child);
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "still running");
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(status.code(), Some(130));
+    drop(stdin);
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,48 @@
+//! Malformed definitions, reported the same way by every backend.
+
+use clacjit::{ClacError, ExecutionBackend, Outcome, State};
+
+fn backends() -> Vec<fn() -> Box<dyn ExecutionBackend>> {
+    vec![
+        || Box::<clacjit::InterpreterBackend>::default(),
+        || Box::new(clacjit::bytecode::BytecodeBackend::new()),
+        #[cfg(feature = "jit")]
+        || Box::new(clacjit::jit::JitBackend::new()),
+    ]
+}
+
+fn run(backend: Box<dyn ExecutionBackend>, source: &str) -> (Vec<i32>, Result<Outcome, ClacError>) {
+    let mut state = State::with_backend(backend);
+    let result = state.run_str_outcome(source).map(|(outcome, _)| outcome);
+    let stack = state.stack().to_vec();
+    // The state is still usable
+    assert_eq!(state.run_str("1 2 +").unwrap().last(), Some(&3));
+    (stack, result)
+}
+
+#[test]
+fn malformed_definitions_agree() {
+    let cases = [
+        (": f : g ; 1 2", ClacError::InvalidDefinition),
+        (": f ; ;", ClacError::UnexpectedDefinitionEnd),
+        ("1 : f 1 : g 2 ; ;", ClacError::InvalidDefinition),
+        (": ;", ClacError::EmptyDefinition),
+        (": 1 2 ;", ClacError::InvalidDefinition),
+        (": f 1", ClacError::QueueUnderflow),
+    ];
+    for (source, error) in cases {
+        let expected = run(backends()[0](), source);
+        assert_eq!(expected.1, Err(error), "{}", source);
+        for backend in backends() {
+            assert_eq!(run(backend(), source), expected, "{}", source);
+        }
+    }
+}
+
+#[test]
+fn comments_may_hold_anything() {
+    for backend in backends() {
+        let mut state = State::with_backend(backend());
+        assert_eq!(state.run_str(": comment a : b ; 4 5 *").unwrap(), [20]);
+    }
+}
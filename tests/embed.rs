@@ -0,0 +1,111 @@
+//! The embedding API: running source, and reading and changing the stack.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use clacjit::{ClacError, Outcome, StackEffect, State};
+
+/// Output shared with the test
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn run_str_returns_the_stack() {
+    let mut state = State::new();
+    assert_eq!(state.run_str(": sq 1 pick * ; 7 sq").unwrap(), [49]);
+    // The stack carries over to the next run
+    assert_eq!(state.run_str("2 sq").unwrap(), [49, 4]);
+    // What is after `quit` is dropped, and quitting is not an error
+    assert_eq!(state.run_str("1 quit 2").unwrap(), [49, 4, 1]);
+    assert_eq!(state.run_str("3").unwrap(), [49, 4, 1, 3]);
+}
+
+#[test]
+fn run_str_outcome_tells_quit_apart() {
+    let mut state = State::new();
+    assert_eq!(
+        state.run_str_outcome(": sq 1 pick * ; 7 sq").unwrap(),
+        (Outcome::Done, [49].as_slice())
+    );
+    assert_eq!(
+        state.run_str_outcome("1 quit 2").unwrap(),
+        (Outcome::Quit, [49, 1].as_slice())
+    );
+    assert_eq!(
+        state.run_str_outcome("3").unwrap(),
+        (Outcome::Done, [49, 1, 3].as_slice())
+    );
+    assert_eq!(
+        state.run_str_outcome("drop drop drop drop"),
+        Err(ClacError::StackUnderflow)
+    );
+}
+
+#[test]
+fn run_str_returns_errors() {
+    let mut state = State::new();
+    assert_eq!(state.run_str("1 0 / 5"), Err(ClacError::DivisionByZero));
+    // The rest of the program was dropped
+    assert!(state.run_str("").unwrap().is_empty());
+    assert_eq!(
+        state.run_str("nope"),
+        Err(ClacError::UnknownDefinition("nope".to_string()))
+    );
+}
+
+#[test]
+fn push_and_clear_values() {
+    let mut state = State::new();
+    state.push_values(&[1, 2, 3]);
+    assert_eq!(state.stack(), [1, 2, 3]);
+    assert_eq!(state.run_str("-").unwrap(), [1, -1]);
+    state.push_values(&[]);
+    state.push_values(&[7]);
+    assert_eq!(state.stack(), [1, -1, 7]);
+
+    state.clear_stack();
+    assert!(state.stack().is_empty());
+    assert_eq!(state.run_str("drop"), Err(ClacError::StackUnderflow));
+}
+
+#[test]
+fn definitions_are_listed_sorted() {
+    let mut state = State::new();
+    assert!(state.definitions().is_empty());
+    state
+        .run_str(": sq 1 pick * ; : cube 1 pick sq * ;")
+        .unwrap();
+    state.register_native("neg", StackEffect::new(1, 1), |args| Ok(vec![-args[0]]));
+    assert_eq!(state.definitions(), ["cube", "neg", "sq"]);
+
+    // Redefining a word does not list it twice
+    state
+        .run_str(": neg 0 swap - ; : comment not a word ;")
+        .unwrap();
+    assert_eq!(state.definitions(), ["cube", "neg", "sq"]);
+}
+
+#[test]
+fn print_stack_writes_to_the_output() {
+    let mut state = State::new();
+    let output = Output::default();
+    state.set_output(Box::new(output.clone()));
+    state.run_str("1 2 3").unwrap();
+    state.print_stack().unwrap();
+    state.clear_stack();
+    state.print_stack().unwrap();
+    assert_eq!(
+        String::from_utf8(output.0.lock().unwrap().clone()).unwrap(),
+        "Stack: 1 2 3 \nStack: \n"
+    );
+}
@@ -0,0 +1,67 @@
+//! The C API, from a C program built against the `cdylib` and the header.
+
+use std::ffi::CStr;
+
+use std::path::PathBuf;
+use std::process::Command;
+
+#[test]
+fn c_program() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    // Tests run from target/<profile>/deps, where the cdylib is built
+    let exe = std::env::current_exe().unwrap();
+    let lib_dir = exe.parent().unwrap();
+    let program = exe.with_file_name("embed-c");
+
+    let status = Command::new("cc")
+        .arg(root.join("tests/c/embed.c"))
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg("-L")
+        .arg(lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-lclacjit")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-o")
+        .arg(&program)
+        .status()
+        .expect("a C compiler, cc, is needed to test the C API");
+    assert!(status.success(), "compiling tests/c/embed.c failed");
+
+    let output = Command::new(&program).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn header_is_up_to_date() {
+    let generated = concat!(env!("OUT_DIR"), "/clacjit.h");
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let checked_in = std::fs::read_to_string(root.join("include/clacjit.h")).unwrap();
+    assert!(
+        checked_in == include_str!(concat!(env!("OUT_DIR"), "/clacjit.h")),
+        "include/clacjit.h is stale, copy {} over it",
+        generated
+    );
+}
+
+/// Overflow panics in debug builds, which the C API has to catch
+#[cfg(debug_assertions)]
+#[test]
+fn panics_are_errors() {
+    use clacjit::ffi::*;
+
+    unsafe {
+        let state = clac_state_new(CLAC_INTERPRETER);
+        assert_eq!(clac_load(state, c"2147483647 1 +".as_ptr()), CLAC_DONE);
+        assert_eq!(clac_run(state), CLAC_ERROR);
+        let error = CStr::from_ptr(clac_last_error(state)).to_str().unwrap();
+        assert!(error.starts_with("Internal error: "), "{}", error);
+        // The state can still be freed
+        clac_state_free(state);
+    }
+}
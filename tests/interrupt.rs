@@ -0,0 +1,80 @@
+//! Evaluations stopped through the flag from `State::interrupt_handle`.
+
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use clacjit::{ClacError, ExecutionBackend, Position, State};
+
+fn backends() -> Vec<fn() -> Box<dyn ExecutionBackend>> {
+    vec![
+        || Box::<clacjit::InterpreterBackend>::default(),
+        || Box::new(clacjit::bytecode::BytecodeBackend::new()),
+        #[cfg(feature = "jit")]
+        || Box::new(clacjit::jit::JitBackend::new()),
+    ]
+}
+
+#[test]
+fn interrupt_from_another_thread() {
+    for backend in backends() {
+        let mut state = State::with_backend(backend());
+        let interrupt = state.interrupt_handle();
+        let worker =
+            thread::spawn(move || state.run_str(": forever 1 drop forever ; forever").err());
+
+        thread::sleep(Duration::from_millis(50));
+        interrupt.store(true, Ordering::Relaxed);
+        let error = worker.join().unwrap();
+        assert!(
+            matches!(error, Some(ClacError::Interrupted(_))),
+            "{:?}",
+            error
+        );
+    }
+}
+
+#[test]
+fn interrupted_state_runs_again() {
+    for backend in backends() {
+        let mut state = State::with_backend(backend());
+        state
+            .run_str(": sq 1 pick * ; : forever 1 drop forever ;")
+            .unwrap();
+        let interrupt = state.interrupt_handle();
+        let worker = thread::spawn(move || {
+            let error = state.run_str("7 forever 8").err();
+            (state, error)
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        interrupt.store(true, Ordering::Relaxed);
+        let (mut state, error) = worker.join().unwrap();
+        // It stopped inside the loop, and says so
+        assert!(
+            matches!(&error, Some(ClacError::Interrupted(Position::Word(name, _))) if name == "forever"),
+            "{:?}",
+            error
+        );
+        // The flag was cleared, definitions are kept, and the rest of the
+        // program was dropped
+        assert!(!interrupt.load(Ordering::Relaxed));
+        state.clear_stack();
+        assert_eq!(state.run_str("3 sq").unwrap(), [9]);
+    }
+}
+
+#[test]
+fn interrupt_set_before_running() {
+    for backend in backends() {
+        let mut state = State::with_backend(backend());
+        state.run_str(": sq 1 pick * ;").unwrap();
+        state.interrupt_handle().store(true, Ordering::Relaxed);
+        assert!(matches!(
+            state.run_str("3 sq").map(|_| ()),
+            Err(ClacError::Interrupted(_))
+        ));
+        state.clear_stack();
+        assert_eq!(state.run_str("3 sq").unwrap(), [9]);
+    }
+}
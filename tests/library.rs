@@ -0,0 +1,59 @@
+//! How states look up words, between their own definitions and a library's.
+
+use std::sync::Arc;
+
+use clacjit::{ClacError, ExecutionBackend, Library, State};
+
+fn backends() -> Vec<fn() -> Box<dyn ExecutionBackend + Sync>> {
+    vec![
+        || Box::<clacjit::InterpreterBackend>::default(),
+        || Box::new(clacjit::bytecode::BytecodeBackend::new()),
+        #[cfg(feature = "jit")]
+        || Box::new(clacjit::jit::JitBackend::new()),
+    ]
+}
+
+fn state(backend: fn() -> Box<dyn ExecutionBackend + Sync>) -> State {
+    let library = Library::new(backend(), ": inner 1 ; : outer inner 10 + ;").unwrap();
+    let mut state = State::with_library(Arc::new(library));
+    state
+        .run_str(": inner 100 ; : mine inner outer + ;")
+        .unwrap();
+    state
+}
+
+#[test]
+fn own_definitions_shadow_the_library() {
+    for backend in backends() {
+        let mut state = state(backend);
+        assert_eq!(state.run_str("inner").unwrap(), [100]);
+        // `mine` calls its state's `inner`, and `outer` the library's
+        assert_eq!(state.run_str("mine").unwrap(), [100, 111]);
+    }
+}
+
+#[test]
+fn library_words_call_each_other() {
+    for backend in backends() {
+        let mut state = state(backend);
+        assert_eq!(state.run_str("outer").unwrap(), [11]);
+        // Also when stepped through
+        state.clear_stack();
+        state.parse("outer");
+        while state.step().unwrap() != clacjit::Step::Done {}
+        assert_eq!(state.stack(), [11]);
+    }
+}
+
+#[test]
+fn library_does_not_see_the_state() {
+    for backend in backends() {
+        let library = Library::new(backend(), ": inner 1 ; : run hook inner + ;").unwrap();
+        let mut state = State::with_library(Arc::new(library));
+        state.run_str(": inner 100 ; : hook inner * ;").unwrap();
+        assert_eq!(
+            state.run_str("5 run"),
+            Err(ClacError::UnknownDefinition("hook".to_string()))
+        );
+    }
+}
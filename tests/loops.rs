@@ -0,0 +1,122 @@
+//! Self-tail-recursive loops kept in registers, against the interpreter.
+
+#![cfg(feature = "jit")]
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use clacjit::jit::{Checks, JitBackend};
+use clacjit::{ClacError, StackEffect, State};
+
+const WORDS: &str = "
+    : nop ;
+    : dup 1 pick ;
+    : dropN dup if 4 skip nop drop 5 skip swap drop 1 - dropN nop ;
+    : keepDropN dup if 4 skip nop drop 5 skip rot drop 1 - keepDropN nop ;
+    : copy dup if 5 skip nop drop drop 10 skip 2 pick 2 + pick rot rot 1 - copy ;
+    : sumsq dup if 4 skip nop drop 8 skip dup sq rot + swap 1 - sumsq nop ;
+    : countdown dup if 4 skip nop drop 5 skip dup print 1 - countdown nop ;
+    : powers dup if 4 skip nop drop 9 skip swap 2 ** 1000 % swap 1 - powers nop ;
+    : roots dup if 4 skip nop drop 9 skip dup 3 - 2 swap ** drop 1 - roots nop ;
+    : sqrts dup if 4 skip nop drop 9 skip dup 9 swap - sqrt drop 1 - sqrts nop ;
+    : picks dup if 4 skip nop drop 5 skip 3 pick drop 1 - picks nop ;
+    : shout print ;
+    : calls dup if 4 skip nop drop 5 skip dup shout 1 - calls nop ;
+    : checked dup if 4 skip nop drop 4 skip check 1 - checked nop ;
+    : divs dup if 4 skip nop drop 9 skip dup 2 - 100 swap / drop 1 - divs nop ;
+";
+
+/// Programs, and whether they only run correctly with `Checks::Full`
+const INPUTS: &[(&str, bool)] = &[
+    ("1 2 3 4 5 3 dropN", false),
+    ("9 0 dropN", false),
+    ("1 2 3 4 5 4 keepDropN", false),
+    ("5 4 3 2 1 4 2 copy", false),
+    ("0 3 sumsq", false),
+    ("0 20 sumsq", false),
+    ("3 countdown", false),
+    ("3 5 powers", false),
+    ("4 calls", false),
+    ("3 checked", false),
+    ("5 sqrts", false),
+    ("1 2 3 3 picks", false),
+    ("1 divs", false),
+    // Errors in the middle of a loop
+    ("1 2 5 dropN", false),
+    ("1 2 5 keepDropN", false),
+    ("12 sqrts", false),
+    ("5 picks", false),
+    ("1 5 picks", false),
+    ("5 checked", false),
+    ("5 roots", true),
+    ("3 divs", true),
+];
+
+/// Output shared with the test, to compare what was printed
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Run {
+    stack: Vec<i32>,
+    result: Result<(), ClacError>,
+    printed: String,
+}
+
+fn run(source: &str, checks: Option<Checks>) -> Run {
+    let mut state = match checks {
+        None => State::new(),
+        Some(checks) => {
+            let mut backend = JitBackend::new();
+            backend.set_checks(checks);
+            State::with_backend(Box::new(backend))
+        }
+    };
+    let output = Output::default();
+    state.set_output(Box::new(output.clone()));
+    state.register_native("sq", StackEffect::new(1, 1), |args| {
+        Ok(vec![args[0] * args[0]])
+    });
+    state.register_native("check", StackEffect::new(1, 1), |args| match args[0] {
+        2 => Err(ClacError::Native("two".to_string())),
+        n => Ok(vec![n]),
+    });
+    state.register_native("sqrt", StackEffect::new(1, 1), |args| match args[0] {
+        n if n < 0 => Err(ClacError::Native("negative".to_string())),
+        n => Ok(vec![(n as f64).sqrt() as i32]),
+    });
+    state.run_str(WORDS).unwrap();
+    let result = state.run_str(source).map(|_| ());
+    let printed = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    Run {
+        stack: state.stack().to_vec(),
+        result,
+        printed,
+    }
+}
+
+#[test]
+fn loops_agree_with_the_interpreter() {
+    for (input, full_only) in INPUTS {
+        let expected = run(input, None);
+        for checks in [Checks::Underflow, Checks::Full] {
+            if *full_only && checks != Checks::Full {
+                continue;
+            }
+            let actual = run(input, Some(checks));
+            let context = format!("{} with {}", input, checks);
+            assert_eq!(actual.result, expected.result, "{}", context);
+            assert_eq!(actual.stack, expected.stack, "{}", context);
+            assert_eq!(actual.printed, expected.printed, "{}", context);
+        }
+    }
+}
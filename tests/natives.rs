@@ -0,0 +1,62 @@
+//! Natives that panic, reported as errors the same way by every backend.
+
+use clacjit::{ClacError, ExecutionBackend, StackEffect, State};
+
+fn backends() -> Vec<fn() -> Box<dyn ExecutionBackend>> {
+    vec![
+        || Box::<clacjit::InterpreterBackend>::default(),
+        || Box::new(clacjit::bytecode::BytecodeBackend::new()),
+        #[cfg(feature = "jit")]
+        || Box::new(clacjit::jit::JitBackend::new()),
+        #[cfg(feature = "jit")]
+        || {
+            let mut backend = clacjit::jit::JitBackend::new();
+            backend.set_checks(clacjit::jit::Checks::Full);
+            Box::new(backend)
+        },
+    ]
+}
+
+const WORDS: &str = "
+    : nop ;
+    : dup 1 pick ;
+    : twice boom boom ;
+    : booms dup if 4 skip nop drop 4 skip boom 1 - booms nop ;
+";
+
+fn run(backend: Box<dyn ExecutionBackend>, source: &str) -> (Vec<i32>, Result<(), ClacError>) {
+    let mut state = State::with_backend(backend);
+    state.register_native("boom", StackEffect::new(1, 1), |args| match args[0] {
+        2 => panic!("two"),
+        3 => panic!("{} is too many", args[0]),
+        n => Ok(vec![n]),
+    });
+    state.run_str(WORDS).unwrap();
+    let result = state.run_str(source).map(|_| ());
+    let stack = state.stack().to_vec();
+    // The native, and the state, are still usable
+    state.clear_stack();
+    assert_eq!(state.run_str("4 twice").unwrap(), [4]);
+    (stack, result)
+}
+
+#[test]
+fn panics_are_errors() {
+    let cases = [
+        ("1 2 boom 5", "boom panicked: two"),
+        ("1 3 twice", "boom panicked: 3 is too many"),
+        ("1 5 booms", "boom panicked: 3 is too many"),
+    ];
+    for (source, message) in cases {
+        let expected = run(backends()[0](), source);
+        assert_eq!(
+            expected.1,
+            Err(ClacError::Native(message.to_string())),
+            "{}",
+            source
+        );
+        for backend in backends() {
+            assert_eq!(run(backend(), source), expected, "{}", source);
+        }
+    }
+}
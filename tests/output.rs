@@ -0,0 +1,72 @@
+//! What programs print, sent to the output given to `State::set_output`.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use clacjit::{ClacError, ExecutionBackend, State};
+
+fn backends() -> Vec<fn() -> Box<dyn ExecutionBackend>> {
+    vec![
+        || Box::<clacjit::InterpreterBackend>::default(),
+        || Box::new(clacjit::bytecode::BytecodeBackend::new()),
+        #[cfg(feature = "jit")]
+        || Box::new(clacjit::jit::JitBackend::new()),
+    ]
+}
+
+/// Output shared with the test
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An output that can not be written to
+struct Broken;
+
+impl Write for Broken {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::other("broken"))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn print_goes_to_the_output() {
+    let program = "
+        : countdown 1 pick if 3 skip 0 6 skip 1 pick print 1 - countdown ;
+        : show print ;
+        1 print -2 show 3 countdown 42 print
+    ";
+    for backend in backends() {
+        let mut state = State::with_backend(backend());
+        let output = Output::default();
+        state.set_output(Box::new(output.clone()));
+        assert_eq!(state.run_str(program).unwrap(), [0]);
+        let printed = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(printed, "1\n-2\n3\n2\n1\n42\n");
+    }
+}
+
+#[test]
+fn output_errors_are_returned() {
+    for backend in backends() {
+        let mut state = State::with_backend(backend());
+        state.set_output(Box::new(Broken));
+        assert_eq!(
+            state.run_str(": show print ; 1 2 show"),
+            Err(ClacError::Output("broken".to_string()))
+        );
+        assert_eq!(state.stack(), [1]);
+    }
+}
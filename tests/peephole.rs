@@ -0,0 +1,84 @@
+//! Superinstructions against the sequences they fuse, on every backend.
+
+use clacjit::{parse, peephole, ClacError, ExecutionBackend, State};
+
+fn backends() -> Vec<fn() -> Box<dyn ExecutionBackend>> {
+    vec![
+        || Box::<clacjit::InterpreterBackend>::default(),
+        || Box::new(clacjit::bytecode::BytecodeBackend::new()),
+        #[cfg(feature = "jit")]
+        || Box::new(clacjit::jit::JitBackend::new()),
+    ]
+}
+
+fn run(backend: Box<dyn ExecutionBackend>, source: &str) -> (Vec<i32>, Result<(), ClacError>) {
+    let mut state = State::with_backend(backend);
+    let result = state.run_str(source).map(|_| ());
+    (state.stack().to_vec(), result)
+}
+
+/// Run `body` as a definition on each input, against the same body behind a
+/// dynamic skip, which keeps anything from being fused
+fn check(body: &str, fuses: bool, inputs: &[&str]) {
+    let optimized = peephole::optimize(parse(body).unwrap());
+    let fused = optimized.iter().ne(parse(body).unwrap().iter());
+    assert_eq!(fused, fuses, "{}", body);
+
+    for backend in backends() {
+        for input in inputs {
+            let expected = run(backend(), &format!(": f 0 0 + skip {} ; {} f", body, input));
+            let actual = run(backend(), &format!(": f {} ; {} f", body, input));
+            assert_eq!(actual, expected, "{} on {}", body, input);
+        }
+    }
+}
+
+#[test]
+fn dup() {
+    check("1 pick", true, &["5", "4 5", ""]);
+}
+
+#[test]
+fn over() {
+    check("2 pick", true, &["5 6", "4 5 6", "5", ""]);
+}
+
+#[test]
+fn nip() {
+    check("swap drop", true, &["1 2", "1 2 3", "1", ""]);
+}
+
+#[test]
+fn rot_rot() {
+    check("rot rot", true, &["1 2 3", "0 1 2 3", "1 2", ""]);
+}
+
+#[test]
+fn pick_pick_less() {
+    check("2 pick 2 pick <", true, &["1 2", "2 1", "3 3", "1", ""]);
+    check("1 pick 3 pick <", true, &["1 2 3", "3 2 1", "1 2", ""]);
+}
+
+#[test]
+fn if_skip() {
+    let inputs = ["1", "0", "-3", ""];
+    check("if 2 skip 10 20 30", true, &inputs);
+    check("if 1 skip 10 20 30", true, &inputs);
+    check("if 3 skip 10 20 30", true, &inputs);
+    // Skipping past the end is left alone
+    check("if 4 skip 10 20 30", false, &inputs);
+}
+
+#[test]
+fn jumps_into_patterns() {
+    // A false `if` lands on the `pick` of `1 pick`
+    check("if 7 8 1 pick", false, &["3 2 1 1", "3 2 1 0", "0"]);
+    // A skip lands on the `pick` of `2 pick`
+    check("1 skip 2 pick", false, &["5 6 1", "5 6 2", "5 6 3"]);
+    // A false `if` lands on the second `rot`
+    check("if 1 2 rot rot", false, &["7 8 9 1", "7 8 9 0", "7 0"]);
+    // A false `if` lands on the `skip` of `if n skip _`
+    check("if 1 2 if 1 skip 4 5", false, &["1 1", "1 0", "0 1", "0 0"]);
+    // Patterns before the target are still fused
+    check("1 pick if 1 2 3 swap drop", true, &["1", "0", ""]);
+}
@@ -0,0 +1,122 @@
+//! Profiles gathered by jitted code, and hot definitions recompiled with them.
+
+#![cfg(feature = "jit")]
+
+use std::ops::Range;
+
+use clacjit::jit::{JitBackend, HOT_CALLS};
+use clacjit::profile::Specialized;
+use clacjit::State;
+
+/// Absolute value, with a branch the profile sees taken one way, and a skip
+/// without a constant count
+const ABS: &str = ": abs 1 pick 0 < if 0 swap - 1 1 - skip ;";
+
+fn jit() -> State {
+    let mut state = State::with_backend(Box::new(JitBackend::new()));
+    state.enable_profiling();
+    state.run_str(ABS).unwrap();
+    state
+}
+
+fn calls(state: &State) -> u64 {
+    state.profile().unwrap().word("abs").unwrap().calls
+}
+
+fn specialized(state: &State, name: &str) -> Option<Specialized> {
+    state.profile().unwrap().word(name).unwrap().specialized
+}
+
+#[test]
+fn hot_definitions_are_recompiled() {
+    let mut state = jit();
+    let mut interpreter = State::new();
+    interpreter.run_str(ABS).unwrap();
+
+    for i in 0..HOT_CALLS as i32 + 500 {
+        let n = if i % 50 == 0 { i } else { -i };
+        let source = format!("{} abs", n);
+        assert_eq!(
+            state.run_str(&source).unwrap(),
+            interpreter.run_str(&source).unwrap(),
+            "{}",
+            source
+        );
+        state.clear_stack();
+        interpreter.clear_stack();
+    }
+    // The recompiled code does not count calls any more
+    assert_eq!(calls(&state), HOT_CALLS);
+    // It tries the count the skip always had first
+    assert_eq!(
+        specialized(&state, "abs"),
+        Some(Specialized {
+            cold: vec![],
+            skips: vec![(11, 0)],
+        })
+    );
+    let branches = state.profile().unwrap().word("abs").unwrap().branches;
+    assert_eq!(
+        branches
+            .values()
+            .map(|[zero, non_zero]| zero + non_zero)
+            .sum::<u64>(),
+        HOT_CALLS
+    );
+}
+
+#[test]
+fn rare_branches_are_moved_out() {
+    let mut state = jit();
+    // Caps values over 1000, which hardly ever come
+    state
+        .run_str(": nop ; : cap 1 pick 1000 swap < if drop 1000 nop ;")
+        .unwrap();
+    for i in 0..HOT_CALLS as i32 + 500 {
+        if i == HOT_CALLS as i32 / 2 {
+            assert_eq!(specialized(&state, "cap"), None);
+        }
+        let n = if i % 200 == 0 { 5000 + i } else { i % 1000 };
+        state.clear_stack();
+        assert_eq!(state.run_str(&format!("{} cap", n)).unwrap(), [n.min(1000)]);
+    }
+    // The arm of the `if` went after the rest of the code
+    assert_eq!(
+        specialized(&state, "cap"),
+        Some(Specialized {
+            cold: vec![Range { start: 6, end: 9 }],
+            skips: vec![],
+        })
+    );
+}
+
+#[test]
+fn hot_definitions_called_from_words_are_recompiled() {
+    let mut state = jit();
+    state.run_str(": twice abs 2 * ;").unwrap();
+    for i in 0..HOT_CALLS as i32 + 10 {
+        state.clear_stack();
+        assert_eq!(state.run_str(&format!("{} twice", -i)).unwrap(), [2 * i]);
+    }
+    assert_eq!(calls(&state), HOT_CALLS);
+}
+
+#[test]
+fn profiling_enabled_again() {
+    let mut state = jit();
+    for i in 0..HOT_CALLS as i32 / 2 {
+        state.run_str(&format!("{} abs drop", -i)).unwrap();
+    }
+    // Code defined before keeps gathering into the old profile, which is
+    // dropped with it
+    state.enable_profiling();
+    for i in 0..HOT_CALLS as i32 {
+        state.clear_stack();
+        assert_eq!(state.run_str(&format!("{} abs", -i)).unwrap(), [i]);
+    }
+    assert!(state.profile().unwrap().word("abs").is_none());
+
+    state.run_str(ABS).unwrap();
+    state.run_str("-3 abs").unwrap();
+    assert_eq!(calls(&state), 1);
+}
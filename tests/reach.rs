@@ -0,0 +1,141 @@
+//! Dead code dropped from definitions, and the warning about it.
+
+use std::sync::{Mutex, Once};
+
+use clacjit::{parse, reach, ClacError, ExecutionBackend, State, Token};
+
+/// Warnings logged so far, by every test in this file
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            WARNINGS.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Warnings about the word `name`
+fn warnings(name: &str) -> Vec<String> {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&Logger).unwrap();
+        log::set_max_level(log::LevelFilter::Warn);
+    });
+    let suffix = format!(" in {}", name);
+    WARNINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|warning| warning.ends_with(&suffix))
+        .cloned()
+        .collect()
+}
+
+fn tokens(source: &str) -> Vec<Token> {
+    parse(source).unwrap().iter().cloned().collect()
+}
+
+fn prune(body: &str, name: &str) -> Vec<Token> {
+    reach::prune(parse(body).unwrap(), name)
+        .iter()
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn code_after_quit() {
+    warnings("after_quit");
+    assert_eq!(prune("1 quit 2 3", "after_quit"), tokens("1 quit"));
+    assert_eq!(
+        warnings("after_quit"),
+        ["2 unreachable tokens in after_quit"]
+    );
+}
+
+#[test]
+fn constant_skips_are_shortened() {
+    warnings("over");
+    assert_eq!(
+        prune("1 skip 5 6 2 skip 7 8 9", "over"),
+        tokens("0 skip 6 0 skip 9")
+    );
+    assert_eq!(warnings("over"), ["3 unreachable tokens in over"]);
+}
+
+#[test]
+fn if_arms_are_kept() {
+    warnings("arms");
+    // The three tokens after an `if` stay, even after `quit`
+    assert_eq!(
+        prune("if quit 1 2 3 quit 4", "arms"),
+        tokens("if quit 1 2 3 quit")
+    );
+    assert_eq!(warnings("arms"), ["1 unreachable token in arms"]);
+}
+
+#[test]
+fn live_code_is_left_alone() {
+    warnings("live");
+    for body in [
+        "1 2 +",
+        "if 1 skip 2 3",
+        "0 0 + skip quit 1",
+        "1 skip 2 skip 3",
+    ] {
+        assert_eq!(prune(body, "live"), tokens(body), "{}", body);
+    }
+    assert!(warnings("live").is_empty());
+}
+
+fn backends() -> Vec<fn() -> Box<dyn ExecutionBackend>> {
+    vec![
+        || Box::<clacjit::InterpreterBackend>::default(),
+        || Box::new(clacjit::bytecode::BytecodeBackend::new()),
+        #[cfg(feature = "jit")]
+        || Box::new(clacjit::jit::JitBackend::new()),
+    ]
+}
+
+fn run(
+    backend: Box<dyn ExecutionBackend>,
+    source: &str,
+) -> (Vec<i32>, Result<clacjit::Outcome, ClacError>) {
+    let mut state = State::with_backend(backend);
+    state.parse(source);
+    let result = clacjit::eval(&mut state);
+    (state.stack().to_vec(), result)
+}
+
+#[test]
+fn pruned_definitions_run_the_same() {
+    let cases = [
+        ("1 quit 2 3", ""),
+        ("1 skip 5 6 2 skip 7 8 9", ""),
+        ("if 2 skip 10 20 30 3 skip 40 50 60 70", "1"),
+        ("if 2 skip 10 20 30 3 skip 40 50 60 70", "0"),
+        ("if quit 1 2 3 quit 4", "0"),
+        ("if quit 1 2 3 quit 4", "1"),
+        ("1 skip 5 drop", ""),
+        ("2 skip 5 6", ""),
+    ];
+    for backend in backends() {
+        for (body, input) in cases {
+            // Nothing is pruned behind a dynamic skip
+            let expected = run(
+                backend(),
+                &format!(": f 0 0 + skip {} ; {} f 100", body, input),
+            );
+            let actual = run(backend(), &format!(": f {} ; {} f 100", body, input));
+            assert_eq!(actual, expected, "{} on {}", body, input);
+        }
+    }
+}
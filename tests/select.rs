@@ -0,0 +1,84 @@
+//! Diamonds compiled to selects, against the interpreter.
+
+#![cfg(feature = "jit")]
+
+use clacjit::jit::{Checks, JitBackend};
+use clacjit::{ClacError, State};
+
+const WORDS: &str = "
+    : nop ;
+    : max 2 pick 2 pick < if 4 skip nop drop 2 skip swap drop nop ;
+    : min 2 pick 2 pick < if 5 skip nop swap drop 1 skip drop ;
+    : pos 0 swap < if 4 skip nop 0 2 skip 1 nop ;
+    : swapif if swap 1 skip nop ;
+    : top7 if 7 swap drop ;
+    : rots if 4 skip nop rot 2 skip rot rot nop ;
+";
+
+const INPUTS: &[&str] = &[
+    "3 5 max",
+    "5 3 max",
+    "-4 -4 max",
+    "3 5 min",
+    "5 3 min",
+    "9 5 min 4 max",
+    "7 pos -7 pos 0 pos",
+    "1 2 1 swapif",
+    "1 2 0 swapif",
+    "1 2 3 top7 4 0 top7",
+    "1 2 3 1 rots",
+    "1 2 3 0 rots",
+    // Too few values under the condition
+    "5 max",
+    "1 1 swapif",
+    "1 top7",
+    "1 2 1 rots",
+    "0 rots",
+];
+
+fn jit(checks: Checks) -> State {
+    let mut backend = JitBackend::new();
+    backend.set_checks(checks);
+    State::with_backend(Box::new(backend))
+}
+
+fn run(source: &str, checks: Option<Checks>) -> (Vec<i32>, Result<(), ClacError>) {
+    let state = match checks {
+        None => State::new(),
+        Some(checks) => jit(checks),
+    };
+    run_on(state, source)
+}
+
+fn run_on(mut state: State, source: &str) -> (Vec<i32>, Result<(), ClacError>) {
+    state.run_str(WORDS).unwrap();
+    let result = state.run_str(source).map(|_| ());
+    (state.stack().to_vec(), result)
+}
+
+#[test]
+fn selects_agree_with_the_interpreter() {
+    for input in INPUTS {
+        let expected = run(input, None);
+        let actual = run(input, Some(Checks::Underflow));
+        assert_eq!(actual.1, expected.1, "{}", input);
+        // A select checks its inputs before taking anything, so it leaves
+        // the stack as it was when it fails
+        if expected.1.is_ok() {
+            assert_eq!(actual.0, expected.0, "{}", input);
+        }
+    }
+}
+
+#[test]
+fn full_checks_fail_like_the_interpreter() {
+    for input in INPUTS {
+        let expected = run(input, None);
+        for backend in [
+            State::with_backend(Box::new(clacjit::bytecode::BytecodeBackend::new())),
+            jit(Checks::Full),
+        ] {
+            assert_eq!(run_on(backend, input), expected, "{}", input);
+        }
+    }
+}
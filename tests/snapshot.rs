@@ -0,0 +1,149 @@
+//! States saved to a snapshot and loaded back.
+
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use clacjit::{ClacError, ExecutionBackend, Outcome, State, Step};
+
+fn backends() -> Vec<fn() -> Box<dyn ExecutionBackend>> {
+    vec![
+        || Box::<clacjit::InterpreterBackend>::default(),
+        || Box::new(clacjit::bytecode::BytecodeBackend::new()),
+        #[cfg(feature = "jit")]
+        || Box::new(clacjit::jit::JitBackend::new()),
+    ]
+}
+
+/// A file of its own for each test
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("clacjit-snapshot-{}-{}", std::process::id(), name))
+}
+
+const WORDS: &str = ": inc 1 + ; : twice inc inc ; : abs 1 pick 0 < if 0 swap - ;";
+
+#[test]
+fn definitions_and_stack_round_trip() {
+    let file = path("round_trip");
+    for save in backends() {
+        let mut state = State::with_backend(save());
+        state.run_str(WORDS).unwrap();
+        state.run_str("7 -3").unwrap();
+        state.save_snapshot(&file, false).unwrap();
+
+        for load in backends() {
+            let mut loaded = State::with_backend(load());
+            loaded.run_str("99 99").unwrap();
+            loaded.load_snapshot(&file).unwrap();
+            assert_eq!(loaded.definitions(), ["abs", "inc", "twice"]);
+            assert_eq!(loaded.stack(), [7, -3]);
+            assert_eq!(loaded.run_str("abs twice").unwrap(), [7, 5]);
+        }
+    }
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn paused_program_resumes() {
+    let file = path("paused");
+    let program = format!("{} 1 twice 10 + 2 skip 5 6 quit 7", WORDS);
+    let mut whole = State::new();
+    assert_eq!(
+        whole.run_str_outcome(&program).unwrap(),
+        (Outcome::Quit, [13].as_slice())
+    );
+
+    let mut state = State::new();
+    state.parse(&program);
+    // Step into `inc`, called from `twice`
+    while state.step().unwrap() != Step::Called("inc".to_string()) {}
+    state.save_snapshot(&file, true).unwrap();
+
+    let mut loaded = State::new();
+    loaded.load_snapshot(&file).unwrap();
+    assert_eq!(loaded.stack(), [1]);
+    let mut steps = Vec::new();
+    loop {
+        match loaded.step().unwrap() {
+            Step::Done => break,
+            step => steps.push(step),
+        }
+    }
+    // The saved calls return to their callers, after `twice` calls `inc`
+    // once more
+    assert_eq!(steps.iter().filter(|s| **s == Step::Returned).count(), 3);
+    assert!(steps.contains(&Step::Called("inc".to_string())));
+    assert_eq!(steps.last(), Some(&Step::Quit));
+    assert_eq!(loaded.stack(), whole.stack());
+
+    // Without the pending program, only the stack is kept
+    state.save_snapshot(&file, false).unwrap();
+    let mut loaded = State::new();
+    loaded.load_snapshot(&file).unwrap();
+    assert_eq!(loaded.step().unwrap(), Step::Done);
+    assert_eq!(loaded.stack(), [1]);
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn malformed_files_change_nothing() {
+    let file = path("malformed");
+    let cases = [
+        "",
+        "clacjit snapshot 0\nstack 1",
+        "clacjit snapshot 1\nstack 1 two",
+        "clacjit snapshot 1\nheap 1",
+        "clacjit snapshot 1\ndef 3 1 +",
+        "clacjit snapshot 1\ndef  1 +",
+        "clacjit snapshot 1\ndef f : g 1 ;",
+        "clacjit snapshot 1\ndef f 1 ; 2",
+        "clacjit snapshot 1\nstack 1\ncall f 2",
+    ];
+    for contents in cases {
+        std::fs::write(&file, contents).unwrap();
+        let mut state = State::new();
+        state.run_str(": f 1 + ; 5").unwrap();
+        let error = state.load_snapshot(&file).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData, "{:?}", contents);
+        assert_eq!(state.definitions(), ["f"]);
+        assert_eq!(state.run_str("f").unwrap(), [6], "{:?}", contents);
+    }
+    std::fs::remove_file(file).unwrap();
+
+    let mut state = State::new();
+    let error = state.load_snapshot(path("missing")).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn limits_are_checked() {
+    let file = path("limits");
+    let mut state = State::new();
+    state.run_str(WORDS).unwrap();
+    state.parse("1 2 3 twice");
+    while state.step().unwrap() != Step::Called("inc".to_string()) {}
+    state.save_snapshot(&file, true).unwrap();
+
+    for (stack, calls, error) in [
+        (Some(2), None, ClacError::StackOverflow),
+        (None, Some(1), ClacError::ReturnStackOverflow),
+    ] {
+        let mut loaded = State::new();
+        loaded.set_max_stack(stack);
+        loaded.set_max_return_stack(calls);
+        let got = loaded.load_snapshot(&file).unwrap_err();
+        assert_eq!(got.kind(), ErrorKind::InvalidData);
+        assert_eq!(got.into_inner().unwrap().to_string(), error.to_string());
+        assert!(loaded.definitions().is_empty());
+        assert!(loaded.stack().is_empty());
+    }
+
+    // Right at the limits is fine
+    let mut loaded = State::new();
+    loaded.set_max_stack(Some(3));
+    loaded.set_max_return_stack(Some(2));
+    loaded.load_snapshot(&file).unwrap();
+    assert_eq!(loaded.stack(), [1, 2, 3]);
+    loaded.set_max_stack(None);
+    assert_eq!(loaded.run_str("").unwrap(), [1, 2, 5]);
+    std::fs::remove_file(file).unwrap();
+}
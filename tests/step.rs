@@ -0,0 +1,93 @@
+//! Programs run one step at a time.
+
+use clacjit::{ClacError, Outcome, State, Step, Token};
+
+fn steps(state: &mut State, source: &str) -> Vec<Step> {
+    state.parse(source);
+    let mut steps = Vec::new();
+    loop {
+        let step = state.step().unwrap();
+        steps.push(step.clone());
+        if step == Step::Done {
+            return steps;
+        }
+    }
+}
+
+fn ran(token: Token, popped: &[i32], pushed: &[i32]) -> Step {
+    Step::Ran {
+        token,
+        popped: popped.to_vec(),
+        pushed: pushed.to_vec(),
+    }
+}
+
+const PROGRAM: &str = ": inc 1 + ; 4 2 inc 1 skip 5 quit 9";
+
+#[test]
+fn words_are_stepped_into() {
+    assert_eq!(
+        steps(&mut State::new(), PROGRAM),
+        [
+            Step::Defined("inc".to_string()),
+            ran(Token::Num(4), &[], &[4]),
+            ran(Token::Num(2), &[], &[2]),
+            Step::Called("inc".to_string()),
+            ran(Token::Num(1), &[], &[1]),
+            ran(Token::Add, &[2, 1], &[3]),
+            Step::Returned,
+            ran(Token::Num(1), &[], &[1]),
+            Step::Skipped {
+                token: Token::Skip,
+                popped: vec![1],
+                count: 1,
+            },
+            Step::Quit,
+            Step::Done,
+        ]
+    );
+}
+
+#[test]
+fn other_backends_run_words_whole() {
+    let mut state = State::with_backend(Box::new(clacjit::bytecode::BytecodeBackend::new()));
+    assert_eq!(
+        steps(&mut state, PROGRAM),
+        [
+            Step::Defined("inc".to_string()),
+            ran(Token::Num(4), &[], &[4]),
+            ran(Token::Num(2), &[], &[2]),
+            // The value below the argument is left out
+            ran(Token::Custom("inc".to_string()), &[2], &[3]),
+            ran(Token::Num(1), &[], &[1]),
+            Step::Skipped {
+                token: Token::Skip,
+                popped: vec![1],
+                count: 1,
+            },
+            Step::Quit,
+            Step::Done,
+        ]
+    );
+}
+
+#[test]
+fn steps_end_like_eval() {
+    let mut stepped = State::new();
+    steps(&mut stepped, PROGRAM);
+    let mut state = State::new();
+    assert_eq!(
+        state.run_str_outcome(PROGRAM).unwrap(),
+        (Outcome::Quit, [4, 3].as_slice())
+    );
+    assert_eq!(stepped.stack(), state.stack());
+
+    stepped.parse("1 0 / 7");
+    assert_eq!(stepped.step().unwrap(), ran(Token::Num(1), &[], &[1]));
+    assert_eq!(stepped.step().unwrap(), ran(Token::Num(0), &[], &[0]));
+    assert_eq!(stepped.step(), Err(ClacError::DivisionByZero));
+    // The rest of the queue was dropped
+    assert_eq!(stepped.step().unwrap(), Step::Done);
+    assert_eq!(state.run_str("1 0 / 7"), Err(ClacError::DivisionByZero));
+    assert_eq!(stepped.stack(), state.stack());
+}
@@ -0,0 +1,131 @@
+//! States moved to, and run on, other threads.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use clacjit::{ExecutionBackend, Library, StackEffect, State};
+
+/// Sum of 1 to n, through two words calling each other
+const TRI: &str = ": nop ; : rec 1 pick 1 - tri + ; : tri 1 pick if rec 1 skip nop ;";
+
+fn backends() -> Vec<fn() -> Box<dyn ExecutionBackend + Sync>> {
+    vec![
+        || Box::<clacjit::InterpreterBackend>::default(),
+        || Box::new(clacjit::bytecode::BytecodeBackend::new()),
+        #[cfg(feature = "jit")]
+        || Box::new(clacjit::jit::JitBackend::new()),
+    ]
+}
+
+#[test]
+fn state_is_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<State>();
+}
+
+#[test]
+fn pool_of_states() {
+    for backend in backends() {
+        let workers: Vec<_> = (0..8)
+            .map(|worker| {
+                thread::spawn(move || {
+                    let mut state = State::with_backend(backend());
+                    state.run_str(TRI).unwrap();
+                    (0..50)
+                        .map(|i| {
+                            let n = worker * 50 + i;
+                            state.clear_stack();
+                            state.run_str(&format!("{} tri", n)).unwrap()[0]
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        for (worker, handle) in workers.into_iter().enumerate() {
+            let sums = handle.join().unwrap();
+            for (i, sum) in sums.into_iter().enumerate() {
+                let n = (worker * 50 + i) as i32;
+                assert_eq!(sum, n * (n + 1) / 2);
+            }
+        }
+    }
+}
+
+#[test]
+fn state_built_on_one_thread_runs_on_another() {
+    for backend in backends() {
+        let mut state = State::with_backend(backend());
+        state.run_str(TRI).unwrap();
+        let sum = thread::spawn(move || state.run_str("100 tri").unwrap().to_vec())
+            .join()
+            .unwrap();
+        assert_eq!(sum, [5050]);
+    }
+}
+
+#[test]
+fn paused_program_resumes_on_another_thread() {
+    let mut state = State::new();
+    state.parse(": sq 1 pick * ; 6 sq 7 sq +");
+    for _ in 0..4 {
+        state.step().unwrap();
+    }
+    let stack = thread::spawn(move || {
+        clacjit::eval(&mut state).unwrap();
+        state.stack().to_vec()
+    })
+    .join()
+    .unwrap();
+    assert_eq!(stack, [85]);
+}
+
+#[test]
+fn library_shared_by_many_states() {
+    for backend in backends() {
+        let library = Arc::new(Library::new(backend(), TRI).unwrap());
+        let workers: Vec<_> = (0..8)
+            .map(|worker| {
+                let library = library.clone();
+                thread::spawn(move || {
+                    let mut state = State::with_library(library);
+                    // Own definitions can call the library's
+                    state.run_str(": twice tri 2 * ;").unwrap();
+                    state
+                        .run_str(&format!("{} twice", worker))
+                        .unwrap()
+                        .to_vec()
+                })
+            })
+            .collect();
+
+        for (worker, handle) in workers.into_iter().enumerate() {
+            let n = worker as i32;
+            assert_eq!(handle.join().unwrap(), [n * (n + 1)]);
+        }
+    }
+}
+
+#[test]
+fn natives_run_on_many_threads() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let workers: Vec<_> = (0..8)
+        .map(|_| {
+            let calls = calls.clone();
+            thread::spawn(move || {
+                let mut state = State::new();
+                state.register_native("count", StackEffect::new(1, 1), move |args| {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    Ok(vec![args[0] + 1])
+                });
+                state.run_str("1 count count count").unwrap().to_vec()
+            })
+        })
+        .collect();
+
+    for handle in workers {
+        assert_eq!(handle.join().unwrap(), [4]);
+    }
+    assert_eq!(calls.load(Ordering::Relaxed), 24);
+}